@@ -0,0 +1,334 @@
+use async_compression::{
+    tokio::{bufread::GzipDecoder, write::GzipEncoder},
+    Level,
+};
+
+use async_zip::{tokio::write::ZipFileWriter, Compression, DeflateOption, ZipEntryBuilder};
+
+use chrono::Utc;
+
+use futures::TryStreamExt;
+
+use serde::{Deserialize, Serialize};
+
+use std::{
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    pin::Pin,
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncSeekExt, AsyncWriteExt, BufReader},
+};
+
+use tokio_util::compat::FuturesAsyncWriteCompatExt;
+
+use crate::error;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ArchiveFormat {
+    #[default]
+    Zip,
+    TarGz,
+}
+
+impl ArchiveFormat {
+    pub fn parse(format: &str) -> Option<Self> {
+        match format.trim().to_ascii_lowercase().as_str() {
+            "zip" => Some(Self::Zip),
+            "tar.gz" | "tgz" => Some(Self::TarGz),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Zip => "zip",
+            Self::TarGz => "tar.gz",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Zip => "application/zip",
+            Self::TarGz => "application/gzip",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchiveEntry {
+    pub name: String,
+    /// Uncompressed size in bytes
+    pub size: u64,
+}
+
+/// Lists the files in the archive at `path` without extracting any of them
+pub async fn list_entries(format: ArchiveFormat, path: &Path) -> io::Result<Vec<ArchiveEntry>> {
+    match format {
+        ArchiveFormat::Zip => {
+            // Only the central directory at the end of the zip gets read
+            let reader = async_zip::tokio::read::fs::ZipFileReader::new(path)
+                .await
+                .map_err(|err| error::io_other(&err.to_string()))?;
+
+            Ok(reader
+                .file()
+                .entries()
+                .iter()
+                .map(|stored| ArchiveEntry {
+                    name: stored.entry().filename().to_owned(),
+                    size: stored.entry().uncompressed_size(),
+                })
+                .collect())
+        }
+        ArchiveFormat::TarGz => {
+            // Tarballs have no index, so the whole thing has to be walked
+            let file = File::open(path).await?;
+            let mut archive = tokio_tar::Archive::new(GzipDecoder::new(BufReader::new(file)));
+
+            let mut entries = Vec::new();
+            let mut stream = archive.entries()?;
+            while let Some(entry) = stream.try_next().await? {
+                entries.push(ArchiveEntry {
+                    name: entry.path()?.to_string_lossy().into_owned(),
+                    size: entry.header().size()?,
+                });
+            }
+
+            Ok(entries)
+        }
+    }
+}
+
+pub type EntryReader = Pin<Box<dyn AsyncRead + Send>>;
+
+/// Opens a single file in the archive at `path` for reading, `None` if there is
+/// no file called `name` in it
+pub async fn open_entry(
+    format: ArchiveFormat,
+    path: &Path,
+    name: &str,
+) -> io::Result<Option<(ArchiveEntry, EntryReader)>> {
+    match format {
+        ArchiveFormat::Zip => {
+            let file = File::open(path).await?;
+            let reader = async_zip::tokio::read::seek::ZipFileReader::new(file)
+                .await
+                .map_err(|err| error::io_other(&err.to_string()))?;
+
+            let Some((index, entry)) = reader
+                .file()
+                .entries()
+                .iter()
+                .enumerate()
+                .find(|(_, stored)| stored.entry().filename() == name)
+                .map(|(index, stored)| {
+                    let entry = ArchiveEntry {
+                        name: name.to_owned(),
+                        size: stored.entry().uncompressed_size(),
+                    };
+                    (index, entry)
+                })
+            else {
+                return Ok(None);
+            };
+
+            let entry_reader = reader
+                .into_entry(index)
+                .await
+                .map_err(|err| error::io_other(&err.to_string()))?;
+
+            Ok(Some((entry, Box::pin(entry_reader))))
+        }
+        ArchiveFormat::TarGz => {
+            let file = File::open(path).await?;
+            let mut archive = tokio_tar::Archive::new(GzipDecoder::new(BufReader::new(file)));
+
+            let mut stream = archive.entries()?;
+            while let Some(entry) = stream.try_next().await? {
+                if entry.path()?.to_string_lossy() == name {
+                    let found = ArchiveEntry {
+                        name: name.to_owned(),
+                        size: entry.header().size()?,
+                    };
+                    return Ok(Some((found, Box::pin(entry))));
+                }
+            }
+
+            Ok(None)
+        }
+    }
+}
+
+pub enum ArchiveWriter {
+    Zip {
+        writer: ZipFileWriter<File>,
+        level: Option<u32>,
+    },
+    // Tar headers carry the entry size up front, so each entry is spooled to
+    // disk before it can be appended
+    TarGz {
+        builder: tokio_tar::Builder<GzipEncoder<File>>,
+        spool: PathBuf,
+    },
+}
+
+impl ArchiveWriter {
+    pub const MIN_LEVEL: u32 = 0;
+    pub const MAX_LEVEL: u32 = 9;
+
+    /// `level` is a deflate level from `MIN_LEVEL` to `MAX_LEVEL`, left to the
+    /// compressor's default when unset
+    pub async fn create(
+        format: ArchiveFormat,
+        level: Option<u32>,
+        path: &Path,
+    ) -> io::Result<Self> {
+        let file = File::create(path).await?;
+
+        Ok(match format {
+            // Streamed entries always get Zip64 sizes as theirs aren't known up front,
+            // which already puts the archive in Zip64 mode. Forcing it covers empty
+            // archives too, so every archive ends with the same Zip64 records.
+            ArchiveFormat::Zip => Self::Zip {
+                writer: ZipFileWriter::new(file).force_zip64(),
+                level,
+            },
+            ArchiveFormat::TarGz => Self::TarGz {
+                builder: tokio_tar::Builder::new(GzipEncoder::with_quality(
+                    file,
+                    level.map_or(Level::Default, |level| Level::Precise(level as i32)),
+                )),
+                spool: path.with_extension("spool"),
+            },
+        })
+    }
+
+    /// Streams `reader` into a new entry, returning the number of bytes read.
+    /// `compression` only applies to zips, tarballs are gzipped as a whole.
+    pub async fn write_entry<R>(
+        &mut self,
+        name: &str,
+        compression: Compression,
+        reader: &mut R,
+    ) -> io::Result<u64>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        match self {
+            Self::Zip { writer, level } => {
+                let mut builder = ZipEntryBuilder::new(name.to_owned(), compression);
+                if let Some(level) = level {
+                    builder = builder.deflate_option(DeflateOption::Other(*level));
+                }
+                let mut entry_writer = writer
+                    .write_entry_stream(builder)
+                    .await
+                    .map_err(|err| error::io_other(&err.to_string()))?
+                    .compat_write();
+
+                let copied = tokio::io::copy(reader, &mut entry_writer).await?;
+
+                entry_writer
+                    .into_inner()
+                    .close()
+                    .await
+                    .map_err(|err| error::io_other(&err.to_string()))?;
+
+                Ok(copied)
+            }
+            Self::TarGz { builder, spool } => {
+                let result = append_spooled(builder, spool, name, reader).await;
+                tokio::fs::remove_file(&spool).await.ok();
+                result
+            }
+        }
+    }
+
+    /// Finishes the archive, returning its final size in bytes
+    pub async fn close(self) -> io::Result<u64> {
+        let mut file = match self {
+            Self::Zip { writer, .. } => writer
+                .close()
+                .await
+                .map_err(|err| error::io_other(&err.to_string()))?,
+            Self::TarGz { builder, .. } => {
+                let mut encoder = builder.into_inner().await?;
+                encoder.shutdown().await?;
+                encoder.into_inner()
+            }
+        };
+
+        file.shutdown().await?;
+
+        // Everything was written front to back, so where it ended is the size
+        file.stream_position().await
+    }
+}
+
+async fn append_spooled<R>(
+    builder: &mut tokio_tar::Builder<GzipEncoder<File>>,
+    spool: &Path,
+    name: &str,
+    reader: &mut R,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    let mut spooled = File::create(spool).await?;
+    let copied = tokio::io::copy(reader, &mut spooled).await?;
+    spooled.seek(SeekFrom::Start(0)).await?;
+
+    let mut header = tokio_tar::Header::new_gnu();
+    header.set_size(copied);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp().max(0) as u64);
+
+    builder.append_data(&mut header, name, spooled).await?;
+
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util;
+
+    // Zip64 end of central directory record, and the locator pointing at it
+    const ZIP64_END: &[u8] = b"PK\x06\x06";
+    const ZIP64_LOCATOR: &[u8] = b"PK\x06\x07";
+
+    async fn zip_of(files: &[(&str, &str)]) -> Vec<u8> {
+        let path = test_util::temp_dir().join("test.zip");
+        let mut writer = ArchiveWriter::create(ArchiveFormat::Zip, None, &path)
+            .await
+            .unwrap();
+        for (name, contents) in files {
+            writer
+                .write_entry(name, Compression::Deflate, &mut contents.as_bytes())
+                .await
+                .unwrap();
+        }
+        writer.close().await.unwrap();
+
+        tokio::fs::read(&path).await.unwrap()
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    #[tokio::test]
+    async fn zips_are_always_zip64() {
+        for files in [&[("cat.txt", "meow")][..], &[]] {
+            let zip = zip_of(files).await;
+            assert!(contains(&zip, ZIP64_END), "{files:?}");
+            assert!(contains(&zip, ZIP64_LOCATOR), "{files:?}");
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use axum::{
+    extract::State,
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use headers::{
+    authorization::{Basic, Bearer},
+    Authorization, HeaderMap, HeaderMapExt,
+};
+
+use rand::distributions::{Alphanumeric, DistString};
+
+use sha2::{Digest, Sha256};
+
+use crate::{config::Config, state::AppState};
+
+pub async fn require_admin<B>(
+    State(state): State<AppState>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if is_admin(&state.config, req.headers()) {
+        next.run(req).await
+    } else {
+        (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"nyazoom\"")],
+        )
+            .into_response()
+    }
+}
+
+// Accepts either `Authorization: Bearer <token>` for scripts, or Basic auth with
+// the token as the password so the admin pages work from a browser prompt
+pub fn is_admin(config: &Config, headers: &HeaderMap) -> bool {
+    let Some(expected) = config.admin_token.as_deref() else {
+        return false;
+    };
+
+    let provided = if let Some(Authorization(bearer)) = headers.typed_get::<Authorization<Bearer>>()
+    {
+        bearer.token().to_owned()
+    } else if let Some(Authorization(basic)) = headers.typed_get::<Authorization<Basic>>() {
+        basic.password().to_owned()
+    } else {
+        return false;
+    };
+
+    constant_time_eq(provided.as_bytes(), expected.as_bytes())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Tokens are secrets, so unlike ids they come from a cryptographically secure rng
+pub fn generate_token() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 32)
+}
+
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, StatusCode};
+
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    use crate::test_util::{self, ADMIN_TOKEN};
+
+    #[tokio::test]
+    async fn admin_routes_need_the_token() {
+        let state = test_util::state().await;
+
+        let response = test_util::send(&state, test_util::get("/records")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(response.headers().contains_key(header::WWW_AUTHENTICATE));
+
+        let mut wrong = test_util::get("/records");
+        wrong
+            .headers_mut()
+            .insert(header::AUTHORIZATION, "Bearer nope".parse().unwrap());
+        let response = test_util::send(&state, wrong).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response =
+            test_util::send(&state, test_util::as_admin(test_util::get("/records"))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn basic_auth_takes_the_token_as_password() {
+        let state = test_util::state().await;
+
+        let mut request = test_util::get("/records/stats");
+        let credentials = STANDARD.encode(format!("admin:{ADMIN_TOKEN}"));
+        request.headers_mut().insert(
+            header::AUTHORIZATION,
+            format!("Basic {credentials}").parse().unwrap(),
+        );
+
+        let response = test_util::send(&state, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
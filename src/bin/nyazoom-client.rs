@@ -0,0 +1,111 @@
+use reqwest::blocking::{multipart::Form, Client};
+
+use serde::Deserialize;
+
+use std::{env, path::PathBuf, process::ExitCode};
+
+static USAGE: &str = "\
+Usage: nyazoom-client [OPTIONS] <SERVER> <FILE>...
+
+Uploads files to a nyazoom instance and prints the download link
+
+Options:
+      --max-downloads <N>  Number of times the upload can be downloaded
+      --expiry-days <N>    Days until the upload expires
+  -h, --help               Print this message";
+
+#[derive(Default)]
+struct Args {
+    server: String,
+    files: Vec<PathBuf>,
+    max_downloads: Option<String>,
+    expiry_days: Option<String>,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = Self::default();
+        let mut positional = Vec::new();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Err(USAGE.to_string()),
+                "--max-downloads" => {
+                    parsed.max_downloads = Some(args.next().ok_or("--max-downloads needs a value")?)
+                }
+                "--expiry-days" => {
+                    parsed.expiry_days = Some(args.next().ok_or("--expiry-days needs a value")?)
+                }
+                flag if flag.starts_with("--") => return Err(format!("unknown option {flag}")),
+                _ => positional.push(arg),
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        parsed.server = positional.next().ok_or(USAGE)?;
+        parsed.files = positional.map(PathBuf::from).collect();
+        if parsed.files.is_empty() {
+            return Err("no files to upload".to_string());
+        }
+
+        Ok(parsed)
+    }
+}
+
+// Only the parts of the server's upload response we care about
+#[derive(Deserialize)]
+struct UploadResponse {
+    url: String,
+}
+
+fn upload(args: Args) -> Result<UploadResponse, String> {
+    // Options have to come before the files for the server to apply them
+    let mut form = Form::new();
+    if let Some(max_downloads) = args.max_downloads {
+        form = form.text("max_downloads", max_downloads);
+    }
+    if let Some(expiry_days) = args.expiry_days {
+        form = form.text("expiry_days", expiry_days);
+    }
+    for file in &args.files {
+        form = form
+            .file("file", file)
+            .map_err(|err| format!("{}: {}", file.display(), err))?;
+    }
+
+    let url = format!("{}/api/upload", args.server.trim_end_matches('/'));
+    let response = Client::new()
+        .post(url)
+        .multipart(form)
+        .send()
+        .map_err(|err| err.to_string())?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().unwrap_or_default();
+        return Err(format!("upload failed ({status}): {body}"));
+    }
+
+    response.json().map_err(|err| err.to_string())
+}
+
+fn main() -> ExitCode {
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match upload(args) {
+        Ok(uploaded) => {
+            println!("{}", uploaded.url);
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -1,40 +1,279 @@
-use crate::state::AppState;
+use crate::{state::UploadRecord, util};
 
 use super::error;
 
-use serde::Serialize;
-use tokio::io::AsyncReadExt;
+use chrono::{DateTime, Utc};
+use flate2::{bufread::GzDecoder, write::GzEncoder};
+use serde::Deserialize;
 
-use std::io;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+};
 
-use std::collections::HashMap;
+// bincode can't skip or default missing fields, so the cache is prefixed with a
+// version that has to be bumped whenever UploadRecord changes shape
+static CACHE_MAGIC: &[u8; 4] = b"NYAZ";
+const CACHE_VERSION: u32 = 11;
 
-pub async fn write_to_cache<T, Y>(records: &HashMap<T, Y>) -> io::Result<()>
-where
-    T: Serialize,
-    Y: Serialize,
-{
-    let mut records_cache = tokio::fs::File::create(".cache/data").await.unwrap();
+// Caches from before compression are told apart by not starting with this
+static GZIP_MAGIC: &[u8; 2] = b"\x1f\x8b";
 
-    let mut buf: Vec<u8> = Vec::with_capacity(200);
-    bincode::serialize_into(&mut buf, records).map_err(|err| error::io_other(&err.to_string()))?;
+/// The records encoded for the cache, taken while they're still locked so that
+/// writing them out, the slow part, can wait until the lock is dropped
+pub struct CacheSnapshot {
+    path: PathBuf,
+    // Failing to encode only comes up once the snapshot is written, so callers
+    // have one error to handle
+    bytes: io::Result<Vec<u8>>,
+    taken: u64,
+}
+
+// Orders snapshots, since the one written last isn't always the one taken last
+static SNAPSHOTS_TAKEN: AtomicU64 = AtomicU64::new(0);
+// When the newest snapshot written to each cache was taken, held through every
+// write so they land one at a time
+static SNAPSHOTS_WRITTEN: Mutex<BTreeMap<PathBuf, u64>> = Mutex::new(BTreeMap::new());
+
+pub fn snapshot(path: &Path, records: &HashMap<String, UploadRecord>) -> CacheSnapshot {
+    CacheSnapshot {
+        path: path.to_owned(),
+        bytes: encode_cache(records),
+        taken: SNAPSHOTS_TAKEN.fetch_add(1, Ordering::SeqCst),
+    }
+}
 
-    let bytes_written = tokio::io::copy(&mut buf.as_slice(), &mut records_cache).await?;
+fn encode_cache(records: &HashMap<String, UploadRecord>) -> io::Result<Vec<u8>> {
+    let mut writer = GzEncoder::new(Vec::new(), flate2::Compression::default());
 
-    tracing::debug!("state cache size: {}", bytes_written);
+    writer.write_all(CACHE_MAGIC)?;
+    bincode::serialize_into(&mut writer, &CACHE_VERSION)
+        .and_then(|_| bincode::serialize_into(&mut writer, records))
+        .map_err(|err| error::io_other(&err.to_string()))?;
 
-    Ok(())
+    writer.finish()
 }
 
-pub async fn fetch_cache() -> AppState {
-    let records = if let Ok(file) = tokio::fs::File::open(".cache/data").await.as_mut() {
-        let mut buf: Vec<u8> = Vec::with_capacity(200);
-        file.read_to_end(&mut buf).await.unwrap();
+impl CacheSnapshot {
+    /// Writes the snapshot over the cache, unless a newer one got there first
+    pub async fn write(self) -> io::Result<()> {
+        let bytes = self.bytes?;
+        let (path, taken) = (self.path, self.taken);
+        tokio::task::spawn_blocking(move || write_cache_file(&path, &bytes, taken))
+            .await
+            .map_err(|err| error::io_other(&err.to_string()))?
+    }
+}
 
-        bincode::deserialize_from(&mut buf.as_slice()).unwrap()
-    } else {
-        HashMap::new()
+// The snapshot lands in a temporary file that is renamed over the cache once
+// complete, a crash partway through leaves the last cache intact
+fn write_cache_file(path: &Path, bytes: &[u8], taken: u64) -> io::Result<()> {
+    let mut written = SNAPSHOTS_WRITTEN
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    if written.get(path).is_some_and(|&newest| newest > taken) {
+        return Ok(());
+    }
+
+    // Named uniquely in case an abandoned write is still finishing up
+    let temp_path = path.with_extension(format!("{}.tmp", util::get_random_name(8)));
+
+    let result = (|| {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+
+        tracing::debug!("state cache size: {}", bytes.len());
+
+        std::fs::rename(&temp_path, path)
+    })();
+
+    match result {
+        Ok(()) => {
+            written.insert(path.to_owned(), taken);
+        }
+        Err(_) => {
+            std::fs::remove_file(&temp_path).ok();
+        }
+    }
+    result
+}
+
+// Refuses to hand back an empty map for a cache it can't make sense of, starting
+// fresh would orphan every stored upload and overwrite the cache on first write
+pub async fn fetch_cache(path: &Path) -> io::Result<HashMap<String, UploadRecord>> {
+    let path = path.to_owned();
+    tokio::task::spawn_blocking(move || read_cache_file(&path))
+        .await
+        .map_err(|err| error::io_other(&err.to_string()))?
+}
+
+fn read_cache_file(path: &Path) -> io::Result<HashMap<String, UploadRecord>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        // Nothing has been cached yet
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(err) => return Err(err),
     };
 
-    AppState::new(records)
+    read_cache(BufReader::new(file)).map_err(|err| {
+        error::io_other(&format!(
+            "can't read records cache {}: {}",
+            path.display(),
+            err
+        ))
+    })
+}
+
+fn read_cache<R: BufRead>(mut reader: R) -> bincode::Result<HashMap<String, UploadRecord>> {
+    if reader.fill_buf()?.starts_with(GZIP_MAGIC) {
+        decode_cache(GzDecoder::new(reader))
+    } else {
+        decode_cache(reader)
+    }
+}
+
+fn decode_cache<R: Read>(mut reader: R) -> bincode::Result<HashMap<String, UploadRecord>> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != CACHE_MAGIC {
+        return decode_legacy_cache(magic.as_slice().chain(reader));
+    }
+
+    let version: u32 = bincode::deserialize_from(&mut reader)?;
+    if version != CACHE_VERSION {
+        return Err(Box::new(bincode::ErrorKind::Custom(format!(
+            "cache version {version} isn't one this build can read"
+        ))));
+    }
+
+    bincode::deserialize_from(reader)
+}
+
+// Shape of UploadRecord back when the cache had no version header
+#[derive(Deserialize)]
+struct LegacyUploadRecord {
+    uploaded: DateTime<Utc>,
+    file: PathBuf,
+    downloads: u8,
+    max_downloads: u8,
+}
+
+fn decode_legacy_cache<R: Read>(reader: R) -> bincode::Result<HashMap<String, UploadRecord>> {
+    let records: HashMap<String, LegacyUploadRecord> = bincode::deserialize_from(reader)?;
+
+    Ok(records
+        .into_iter()
+        .map(|(id, legacy)| {
+            let record = UploadRecord {
+                uploaded: legacy.uploaded,
+                file: legacy.file,
+                downloads: legacy.downloads,
+                max_downloads: legacy.max_downloads,
+                ..Default::default()
+            };
+            (id, record)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::Serialize;
+
+    fn cache_bytes<T: Serialize>(version: u32, records: &HashMap<String, T>) -> Vec<u8> {
+        let mut bytes = CACHE_MAGIC.to_vec();
+        bincode::serialize_into(&mut bytes, &version).unwrap();
+        bincode::serialize_into(&mut bytes, records).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn migrates_unversioned_caches() {
+        let uploaded: DateTime<Utc> = "2023-07-01T12:00:00Z".parse().unwrap();
+        let old = HashMap::from([(
+            "cat".to_owned(),
+            (uploaded, PathBuf::from("cat.zip"), 2u8, 5u8),
+        )]);
+        let bytes = bincode::serialize(&old).unwrap();
+
+        let records = read_cache(bytes.as_slice()).unwrap();
+        let record = &records["cat"];
+        assert_eq!(record.uploaded, uploaded);
+        assert_eq!(record.file, PathBuf::from("cat.zip"));
+        assert_eq!(record.downloads, 2);
+        assert_eq!(record.lifetime, UploadRecord::DEFAULT_LIFETIME);
+    }
+
+    #[test]
+    fn refuses_newer_caches() {
+        let records = HashMap::from([("cat".to_owned(), UploadRecord::default())]);
+        assert!(read_cache(cache_bytes(CACHE_VERSION + 1, &records).as_slice()).is_err());
+    }
+
+    #[tokio::test]
+    async fn round_trips_through_disk() {
+        let dir = std::env::temp_dir().join(format!("nyazoom-{}", util::get_random_name(10)));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data");
+
+        let records: HashMap<String, UploadRecord> = (0..5000)
+            .map(|i| {
+                let record = UploadRecord {
+                    size: i,
+                    hash: Some(format!("{i:x}")),
+                    ..UploadRecord::new(PathBuf::from(format!("{i}.zip")))
+                };
+                (format!("record-{i}"), record)
+            })
+            .collect();
+
+        snapshot(&path, &records).write().await.unwrap();
+        let fetched = fetch_cache(&path).await.unwrap();
+
+        assert_eq!(fetched.len(), records.len());
+        for (id, record) in &records {
+            let fetched = &fetched[id];
+            assert_eq!(fetched.file, record.file);
+            assert_eq!(fetched.size, record.size);
+            assert_eq!(fetched.hash, record.hash);
+            assert_eq!(fetched.uploaded, record.uploaded);
+        }
+
+        // Only the cache itself is left behind, no temporary files
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn stale_snapshots_are_not_written() {
+        let dir = std::env::temp_dir().join(format!("nyazoom-{}", util::get_random_name(10)));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data");
+
+        let mut records = HashMap::from([("cat".to_owned(), UploadRecord::default())]);
+        let stale = snapshot(&path, &records);
+        records.insert("dog".to_owned(), UploadRecord::default());
+        let newest = snapshot(&path, &records);
+
+        newest.write().await.unwrap();
+        stale.write().await.unwrap();
+        assert!(fetch_cache(&path).await.unwrap().contains_key("dog"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn missing_cache_is_empty() {
+        let path = std::env::temp_dir().join(format!("nyazoom-{}", util::get_random_name(10)));
+        assert!(fetch_cache(&path).await.unwrap().is_empty());
+    }
 }
@@ -0,0 +1,268 @@
+use async_zip::Compression;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use std::{
+    env,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::util;
+
+/// Largest any single upload may be when `Config::max_upload_size` is unset,
+/// also the hard ceiling on any request body
+pub const UPLOAD_SIZE_CEILING: u64 = 10 * 1024 * 1024 * 1024; // 10GiB
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Where uploads and the records cache are kept
+    pub data_dir: PathBuf,
+    /// Token required to reach the admin routes, either as a bearer token or as
+    /// the password of HTTP Basic auth. Admin routes are locked when unset.
+    pub admin_token: Option<String>,
+    /// Maximum number of uploads a single client may start per `upload_window`,
+    /// `0` disables the limit
+    pub upload_limit: usize,
+    pub upload_window: Duration,
+    /// Externally visible address used when building absolute links, e.g.
+    /// `https://nyazoom.example`. Falls back to the request's `Host` when unset.
+    pub public_url: Option<String>,
+    /// Most files a single upload may contain
+    pub max_entries: usize,
+    /// Largest a single file in an upload may be, in bytes
+    pub max_entry_size: Option<u64>,
+    /// Largest all the files in a single upload may be together, in bytes
+    pub max_upload_size: Option<u64>,
+    /// Longest a file name in an upload may be, in bytes. Longer names are cut
+    /// short, keeping their extension where possible.
+    pub max_name_length: usize,
+    /// Compression used for uploads that don't ask for a method of their own,
+    /// already compressed formats are always stored as is
+    pub compression: Compression,
+    /// How often expired records are swept, one sweep also runs at startup
+    pub sweep_interval: Duration,
+    /// PEM encoded certificate chain, HTTPS is only served when both this and
+    /// `tls_key` are set
+    pub tls_cert: Option<PathBuf>,
+    /// PEM encoded private key for `tls_cert`
+    pub tls_key: Option<PathBuf>,
+    /// Store an HMAC of each uploader's address rather than the address itself,
+    /// still enough to tell whether two uploads came from the same place
+    pub hash_uploader_ips: bool,
+    /// Secret the uploader HMAC is keyed with. A plain hash of an address is
+    /// trivially reversed by hashing every address, so without the key the
+    /// hashes give nothing away. Hashes only match across restarts if it's set.
+    pub uploader_key: Option<String>,
+    /// Total bytes all stored archives may take up, new uploads are turned away
+    /// once it is reached
+    pub max_storage: Option<u64>,
+    /// Whether fetching a single file out of an upload uses up one of its downloads
+    pub count_entry_downloads: bool,
+    /// How long in-flight requests get to finish on shutdown before their
+    /// connections are dropped
+    pub shutdown_timeout: Duration,
+    /// Peers allowed to report the client's address with `X-Forwarded-For` or
+    /// `X-Real-IP`, those headers are ignored from anyone else
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            data_dir: PathBuf::from(".cache"),
+            admin_token: None,
+            upload_limit: 10,
+            upload_window: Duration::from_secs(60 * 60),
+            public_url: None,
+            max_entries: 1000,
+            max_entry_size: None,
+            max_upload_size: None,
+            max_name_length: 255,
+            compression: Compression::Deflate,
+            sweep_interval: Duration::from_secs(15 * 60),
+            tls_cert: None,
+            tls_key: None,
+            hash_uploader_ips: false,
+            uploader_key: None,
+            max_storage: None,
+            count_entry_downloads: true,
+            shutdown_timeout: Duration::from_secs(30),
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+
+        Self {
+            data_dir: env_var("NYAZOOM_DATA_DIR")
+                .map(PathBuf::from)
+                .unwrap_or(defaults.data_dir),
+            admin_token: env_var("NYAZOOM_ADMIN_TOKEN").or(defaults.admin_token),
+            upload_limit: env_parse("NYAZOOM_UPLOAD_LIMIT").unwrap_or(defaults.upload_limit),
+            upload_window: env_parse("NYAZOOM_UPLOAD_WINDOW_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.upload_window),
+            public_url: env_var("NYAZOOM_PUBLIC_URL").or(defaults.public_url),
+            max_entries: env_parse("NYAZOOM_MAX_ENTRIES").unwrap_or(defaults.max_entries),
+            max_entry_size: env_parse("NYAZOOM_MAX_ENTRY_SIZE").or(defaults.max_entry_size),
+            max_upload_size: env_parse("NYAZOOM_MAX_UPLOAD_SIZE").or(defaults.max_upload_size),
+            max_name_length: env_parse("NYAZOOM_MAX_NAME_LENGTH")
+                .filter(|len| *len > 0)
+                .unwrap_or(defaults.max_name_length),
+            compression: env_var("NYAZOOM_COMPRESSION")
+                .and_then(|method| util::parse_compression(&method))
+                .unwrap_or(defaults.compression),
+            sweep_interval: env_parse("NYAZOOM_SWEEP_INTERVAL_SECS")
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.sweep_interval),
+            tls_cert: env_var("NYAZOOM_TLS_CERT")
+                .map(PathBuf::from)
+                .or(defaults.tls_cert),
+            tls_key: env_var("NYAZOOM_TLS_KEY")
+                .map(PathBuf::from)
+                .or(defaults.tls_key),
+            hash_uploader_ips: env_parse("NYAZOOM_HASH_UPLOADER_IPS")
+                .unwrap_or(defaults.hash_uploader_ips),
+            uploader_key: env_var("NYAZOOM_UPLOADER_KEY").or(defaults.uploader_key),
+            max_storage: env_parse("NYAZOOM_MAX_STORAGE").or(defaults.max_storage),
+            count_entry_downloads: env_parse("NYAZOOM_COUNT_ENTRY_DOWNLOADS")
+                .unwrap_or(defaults.count_entry_downloads),
+            shutdown_timeout: env_parse("NYAZOOM_SHUTDOWN_TIMEOUT_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.shutdown_timeout),
+            trusted_proxies: env_var("NYAZOOM_TRUSTED_PROXIES")
+                .map(|proxies| parse_list("NYAZOOM_TRUSTED_PROXIES", &proxies))
+                .unwrap_or(defaults.trusted_proxies),
+        }
+    }
+
+    /// Directory the archives themselves are stored in
+    pub fn serve_dir(&self) -> PathBuf {
+        self.data_dir.join("serve")
+    }
+
+    /// File the records are cached to between runs
+    pub fn data_path(&self) -> PathBuf {
+        self.data_dir.join("data")
+    }
+
+    /// Largest all the files in a single upload may be together, whether they
+    /// were sent along or fetched from a url
+    pub fn upload_size_limit(&self) -> u64 {
+        self.max_upload_size.unwrap_or(UPLOAD_SIZE_CEILING)
+    }
+
+    pub fn base_url(&self, host: &str) -> String {
+        match &self.public_url {
+            Some(url) => url.trim_end_matches('/').to_owned(),
+            None if self.tls().is_some() => format!("https://{}", host),
+            None => format!("http://{}", host),
+        }
+    }
+
+    /// How an uploader's address is kept on their records
+    pub fn uploader_id(&self, ip: IpAddr) -> String {
+        if self.hash_uploader_ips {
+            // main fills in a random key when none was configured
+            let key = self.uploader_key.as_deref().unwrap_or_default();
+            let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes())
+                .expect("HMAC takes keys of any length");
+            mac.update(ip.to_string().as_bytes());
+            format!("{:x}", mac.finalize().into_bytes())
+        } else {
+            ip.to_string()
+        }
+    }
+
+    /// Certificate and key paths, if both were given
+    pub fn tls(&self) -> Option<(&Path, &Path)> {
+        Some((self.tls_cert.as_deref()?, self.tls_key.as_deref()?))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    /// One JSON object per line, for log aggregators
+    Json,
+}
+
+impl LogFormat {
+    // Read on its own as logging has to be up before the rest of the config is
+    // loaded, or its warnings would go nowhere
+    pub fn from_env() -> Self {
+        match env_var("NYAZOOM_LOG_FORMAT").as_deref() {
+            Some("json") => Self::Json,
+            _ => Self::default(),
+        }
+    }
+}
+
+fn env_var(key: &str) -> Option<String> {
+    env::var(key).ok().filter(|value| !value.is_empty())
+}
+
+// Comma separated, skipping (and warning about) anything that doesn't parse
+fn parse_list<T: FromStr>(key: &str, value: &str) -> Vec<T> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .filter_map(|item| match item.parse() {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                tracing::warn!("ignoring invalid entry in {}: {:?}", key, item);
+                None
+            }
+        })
+        .collect()
+}
+
+fn env_parse<T: FromStr>(key: &str) -> Option<T> {
+    let value = env_var(key)?;
+    match value.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            tracing::warn!("ignoring invalid value for {}: {:?}", key, value);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hashing(key: &str) -> Config {
+        Config {
+            hash_uploader_ips: true,
+            uploader_key: Some(key.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn uploader_ids_depend_on_the_key() {
+        let ip = IpAddr::from([203, 0, 113, 7]);
+
+        assert_eq!(
+            hashing("cat").uploader_id(ip),
+            hashing("cat").uploader_id(ip)
+        );
+        assert_ne!(
+            hashing("cat").uploader_id(ip),
+            hashing("dog").uploader_id(ip)
+        );
+        assert_ne!(hashing("cat").uploader_id(ip), ip.to_string());
+        assert_eq!(Config::default().uploader_id(ip), ip.to_string());
+    }
+}
@@ -1,44 +1,58 @@
-use async_zip::{tokio::write::ZipFileWriter, Compression, ZipEntryBuilder};
-
 use axum::{
     body::StreamBody,
-    extract::{ConnectInfo, DefaultBodyLimit, Multipart, State},
-    http::{Request, Response, StatusCode},
+    extract::{ConnectInfo, DefaultBodyLimit, Host, Multipart, Query, State},
+    http::{HeaderValue, Request, Response, StatusCode},
     middleware::{self, Next},
-    response::{Html, IntoResponse, Redirect},
-    routing::{get, post},
-    Json, Router, TypedHeader,
+    response::{Html, IntoResponse},
+    routing::{delete, get, head, post},
+    Json, Router,
 };
 
-use futures::TryStreamExt;
+use axum_server::tls_rustls::RustlsConfig;
+
+use chrono::{DateTime, Utc};
+
+use futures::TryFutureExt;
 
 use headers::HeaderMap;
 use leptos::IntoView;
-use nyazoom_headers::ForwardedFor;
+use qrcode::{render::svg, QrCode};
 
-use sanitize_filename_reader_friendly::sanitize;
+use serde::{Deserialize, Serialize};
 
-use std::{io, net::SocketAddr, path::Path, time::Duration};
+use std::{io, net::SocketAddr, path::PathBuf, time::Duration};
 
-use tokio_util::{
-    compat::FuturesAsyncWriteCompatExt,
-    io::{ReaderStream, StreamReader},
-};
+use tokio_util::io::ReaderStream;
 
 use tower_http::{limit::RequestBodyLimitLayer, services::ServeDir, trace::TraceLayer};
 
+use tracing::Instrument;
+
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod archive;
+mod auth;
 mod cache;
+mod config;
+mod metrics;
 mod nyazoom_headers;
+mod rate_limit;
+mod remote;
 mod state;
+#[cfg(test)]
+mod test_util;
+mod tus;
+mod upload;
 mod util;
 mod views;
 
-use state::{AppState, UploadRecord};
+use config::{Config, LogFormat};
+use metrics::Metrics;
+use state::{AppState, RecordStats};
+use upload::{StoredUpload, UploadResponse};
 
 use crate::state::AsyncRemoveRecord;
-use crate::views::{DownloadLinkPage, HtmxPage, LinkView, Welcome};
+use crate::views::{ContentsView, DownloadLinkPage, HtmxPage, LinkView, NotFoundPage, Welcome};
 
 pub mod error {
     use std::io::{Error, ErrorKind};
@@ -51,118 +65,419 @@ pub mod error {
 #[tokio::main]
 async fn main() -> io::Result<()> {
     // Set up logging
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "nyazoom=debug,tower_http=debug".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    let registry = tracing_subscriber::registry().with(
+        tracing_subscriber::EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| "nyazoom=debug,tower_http=debug".into()),
+    );
+    match LogFormat::from_env() {
+        LogFormat::Pretty => registry.with(tracing_subscriber::fmt::layer()).init(),
+        LogFormat::Json => registry
+            .with(tracing_subscriber::fmt::layer().json())
+            .init(),
+    }
+
+    let mut config = Config::from_env();
+    if config.admin_token.is_none() {
+        tracing::warn!("NYAZOOM_ADMIN_TOKEN is not set, admin routes will reject every request");
+    }
+    if config.tls().is_none() && (config.tls_cert.is_some() || config.tls_key.is_some()) {
+        tracing::warn!("TLS needs both NYAZOOM_TLS_CERT and NYAZOOM_TLS_KEY, serving plain HTTP");
+    }
+    if config.hash_uploader_ips && config.uploader_key.is_none() {
+        tracing::warn!("NYAZOOM_UPLOADER_KEY is not set, uploader hashes will change on restart");
+        config.uploader_key = Some(auth::generate_token());
+    }
 
-    // uses create_dir_all to create both .cache and serve inside it in one go
-    util::make_dir(".cache/serve").await?;
+    // uses create_dir_all to create both the data dir and serve inside it in one go
+    util::make_dir(config.serve_dir()).await?;
 
-    let state = cache::fetch_cache().await;
+    let records = cache::fetch_cache(&config.data_path()).await?;
+    let state = AppState::new(config, records);
 
-    // Spawn a repeating task that will clean files periodically
+    // Spawn a repeating task that will clean files periodically, starting right
+    // away so leftovers from the last run don't linger
     tokio::spawn({
         let state = state.clone();
         async move {
+            let mut interval = tokio::time::interval(state.config.sweep_interval);
             loop {
-                tokio::time::sleep(Duration::from_secs(15 * 60)).await;
+                interval.tick().await;
                 tracing::info!("Cleaning Sweep!");
+                state::cull_expired(&state).await;
+                tus::cull_stale(&state).await;
+                tus::cull_orphaned_files(&state).await;
+            }
+        }
+    });
 
-                let mut records = state.records.lock().await;
-
-                for (key, record) in records.clone().into_iter() {
-                    if !record.can_be_downloaded() {
-                        tracing::info!("culling: {:?}", record);
-                        records.remove_record(&key).await.unwrap();
-                    }
+    // Keep a cat fact on hand so the landing page never waits on the network
+    tokio::spawn({
+        let state = state.clone();
+        async move {
+            let client = reqwest::Client::new();
+            loop {
+                if let Some(fact) = views::get_cat_fact(&client).await {
+                    *state.cat_fact.write().await = Some(fact);
                 }
+                tokio::time::sleep(Duration::from_secs(60)).await;
             }
         }
     });
 
-    // Router Setup
-    let app = Router::new()
-        .route("/", get(welcome))
-        .route("/upload", post(upload_to_zip))
+    let app = app(state.clone());
+
+    // Server creation
+    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
+    serve(&state.config, addr, app).await?;
+
+    // Requests are done or dropped by now, persist whatever they changed
+    tracing::info!("flushing records to cache");
+    let snapshot = cache::snapshot(&state.config.data_path(), &*state.records.lock().await);
+    snapshot.write().await?;
+
+    Ok(())
+}
+
+// Everything but the server itself, so tests can send requests straight in
+fn app(state: AppState) -> Router {
+    let admin = Router::new()
         .route("/records", get(records))
         .route("/records/links", get(records_links))
+        .route("/records/stats", get(records_stats))
+        .route("/records/:id", delete(record_delete))
+        .route("/link/:id/extend", post(link_extend))
+        .route("/admin/cull", post(admin_cull))
+        .route("/admin/delete", post(admin_delete))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_admin,
+        ));
+
+    let uploads = Router::new()
+        .route("/upload", post(upload_to_zip))
+        .route("/api/upload", post(api_upload))
+        .route("/tus", post(tus::create))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::limit_uploads,
+        ));
+
+    Router::new()
+        .route("/", get(welcome))
+        .merge(uploads)
+        .merge(admin)
         .route("/download/:id", get(download))
+        .route("/download/:id/entry/:name", get(download_entry))
         .route("/link/:id", get(link).delete(link_delete))
         .route("/link/:id/remaining", get(remaining))
+        .route("/link/:id/qr", get(link_qr))
+        .route("/link/:id/contents", get(link_contents))
+        // Only creating a tus upload counts towards the rate limit, not every chunk
+        .route("/tus/:id", head(tus::offset).patch(tus::append))
+        .route("/metrics", get(metrics))
         .layer(DefaultBodyLimit::disable())
+        // A hard ceiling on any request, uploads are held to NYAZOOM_MAX_UPLOAD_SIZE
+        // as they're archived, which comes with a clearer error
         .layer(RequestBodyLimitLayer::new(
-            10 * 1024 * 1024 * 1024, // 10GiB
+            config::UPLOAD_SIZE_CEILING as usize,
         ))
-        .with_state(state)
+        .with_state(state.clone())
         .fallback_service(ServeDir::new("dist"))
         .layer(TraceLayer::new_for_http())
-        .layer(middleware::from_fn(log_source));
+        .layer(middleware::from_fn_with_state(state.clone(), log_source))
+        .layer(middleware::from_fn(request_id))
+        // Probes are routed last so they stay out of the request logs
+        .route("/healthz", get(healthz))
+        .route("/readyz", {
+            let serve_dir = state.config.serve_dir();
+            get(move || readyz(serve_dir.clone()))
+        })
+}
 
-    // Server creation
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
-    tracing::debug!("listening on http://{}/", addr);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-        .await
-        .unwrap();
+// Serves over HTTPS when a certificate is configured, plain HTTP otherwise
+async fn serve(config: &Config, addr: SocketAddr, app: Router) -> io::Result<()> {
+    let handle = axum_server::Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        let timeout = config.shutdown_timeout;
+        async move {
+            shutdown_signal().await;
+            // Let in-flight requests finish, but don't let a stalled upload
+            // hold the process up forever
+            handle.graceful_shutdown(Some(timeout));
+        }
+    });
 
-    Ok(())
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    match config.tls() {
+        Some((cert, key)) => {
+            let tls = RustlsConfig::from_pem_file(cert, key).await?;
+            tracing::debug!("listening on https://{}/", addr);
+            axum_server::bind_rustls(addr, tls)
+                .handle(handle)
+                .serve(service)
+                .await
+        }
+        None => {
+            tracing::debug!("listening on http://{}/", addr);
+            axum_server::bind(addr).handle(handle).serve(service).await
+        }
+    }
+}
+
+// Resolves once the process is asked to stop, by Ctrl-C or a SIGTERM on unix
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl-C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    tracing::info!("shutdown signal received, finishing in-flight requests");
+}
+
+#[derive(Serialize)]
+struct Remaining {
+    remaining: u8,
+    expires_at: DateTime<Utc>,
 }
 
 async fn remaining(
     State(state): State<AppState>,
+    headers: HeaderMap,
     axum::extract::Path(id): axum::extract::Path<String>,
-) -> impl IntoResponse {
+) -> axum::response::Response {
     let records = state.records.lock().await;
-    if let Some(record) = records.get(&id) {
-        let downloads_remaining = record.downloads_remaining();
-        let plural = if downloads_remaining > 1 { "s" } else { "" };
-        let out = format!(
-            "You have {} download{} remaining!",
-            downloads_remaining, plural
-        );
-        Html(out)
-    } else {
-        Html("?".to_string())
+    let record = records.get(&id);
+
+    if util::wants_json(&headers) {
+        return match record {
+            Some(record) => Json(Remaining {
+                remaining: record.downloads_remaining(),
+                expires_at: record.expires_at(),
+            })
+            .into_response(),
+            None => (
+                StatusCode::NOT_FOUND,
+                Json(ErrorBody { error: "not found" }),
+            )
+                .into_response(),
+        };
+    }
+
+    match record {
+        Some(record) => Html(views::remaining_message(record.downloads_remaining())),
+        None => Html("?".to_string()),
     }
+    .into_response()
 }
 
-async fn welcome() -> impl IntoResponse {
-    let cat_fact = views::get_cat_fact().await;
+async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let active_records = state.records.lock().await.len();
+
+    (
+        [("Content-Type", "text/plain; version=0.0.4")],
+        state.metrics.render(active_records, state.stored_bytes()),
+    )
+}
+
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+async fn readyz(serve_dir: PathBuf) -> Result<&'static str, (StatusCode, String)> {
+    // Named like any other upload so overlapping probes don't trip over each other
+    let probe = serve_dir.join(format!(".readyz-{}", util::get_random_name(10)));
+
+    tokio::fs::write(&probe, b"")
+        .and_then(|_| tokio::fs::remove_file(&probe))
+        .await
+        .map_err(|err| (StatusCode::SERVICE_UNAVAILABLE, err.to_string()))?;
+
+    Ok("ok")
+}
+
+async fn welcome(State(state): State<AppState>) -> impl IntoResponse {
+    let cat_fact = state
+        .cat_fact
+        .read()
+        .await
+        .clone()
+        .unwrap_or_else(|| views::CAT_FACT_FALLBACK.to_string());
     Html(leptos::ssr::render_to_string(move |cx| {
         leptos::view! { cx, <Welcome fact=cat_fact /> }
     }))
 }
 
-async fn records(State(state): State<AppState>) -> impl IntoResponse {
-    Json(state.records.lock().await.clone())
+const DEFAULT_PER_PAGE: usize = 50;
+const MAX_PER_PAGE: usize = 500;
+
+#[derive(Deserialize)]
+struct Pagination {
+    page: Option<usize>,
+    per_page: Option<usize>,
 }
 
-// This function is to remain ugly until that time in which I properly hide
-// this behind some kind of authentication
-async fn records_links(State(state): State<AppState>) -> impl IntoResponse {
-    let records = state.records.lock().await.clone();
+impl Pagination {
+    /// 1-indexed, anything below that is treated as the first page
+    fn page(&self) -> usize {
+        self.page.unwrap_or(1).max(1)
+    }
+
+    fn per_page(&self) -> usize {
+        self.per_page
+            .unwrap_or(DEFAULT_PER_PAGE)
+            .clamp(1, MAX_PER_PAGE)
+    }
+
+    fn total_pages(&self, total: usize) -> usize {
+        total.div_ceil(self.per_page()).max(1)
+    }
+
+    /// Pages past the end come back empty rather than as an error
+    fn apply<T>(&self, items: Vec<T>) -> Vec<T> {
+        let skip = (self.page() - 1).saturating_mul(self.per_page());
+        items.into_iter().skip(skip).take(self.per_page()).collect()
+    }
+}
+
+#[derive(Serialize)]
+struct RecordsPage {
+    page: usize,
+    per_page: usize,
+    total: usize,
+    total_pages: usize,
+    records: Vec<RecordListing>,
+}
+
+#[derive(Serialize)]
+struct RecordListing {
+    id: String,
+    #[serde(flatten)]
+    record: state::UploadRecord,
+}
+
+async fn records(
+    State(state): State<AppState>,
+    Query(pagination): Query<Pagination>,
+) -> impl IntoResponse {
+    let sorted = state::sorted_records(&*state.records.lock().await);
+    let total = sorted.len();
+
+    Json(RecordsPage {
+        page: pagination.page(),
+        per_page: pagination.per_page(),
+        total,
+        total_pages: pagination.total_pages(total),
+        records: pagination
+            .apply(sorted)
+            .into_iter()
+            .map(|(id, record)| RecordListing { id, record })
+            .collect(),
+    })
+}
+
+async fn records_stats(State(state): State<AppState>) -> impl IntoResponse {
+    Json(RecordStats::collect(&*state.records.lock().await))
+}
+
+async fn admin_cull(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state::cull_expired(&state).await)
+}
+
+#[derive(Deserialize)]
+struct BatchDelete {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+// Takes a JSON array of ids, answering with which of them went and which didn't
+async fn admin_delete(
+    State(state): State<AppState>,
+    Query(options): Query<BatchDelete>,
+    Json(ids): Json<Vec<String>>,
+) -> impl IntoResponse {
+    Json(state::remove_records(&state, &ids, options.dry_run).await)
+}
+
+// This function is to remain ugly, but at least it is behind admin auth now
+async fn records_links(
+    State(state): State<AppState>,
+    Query(pagination): Query<Pagination>,
+) -> impl IntoResponse {
+    let (sorted, stats) = {
+        let records = state.records.lock().await;
+        (
+            state::sorted_records(&records),
+            RecordStats::collect(&records),
+        )
+    };
+    let page = pagination.page();
+    let per_page = pagination.per_page();
+    let total_pages = pagination.total_pages(sorted.len());
+    let records = pagination.apply(sorted);
+    let now = Utc::now();
     Html(leptos::ssr::render_to_string(move |cx| {
         leptos::view! { cx,
             <HtmxPage>
                 <div class="form-wrapper">
                     <div class="column-container">
+                        <p class="link-wrapper">
+                            {stats.active_records} " active, "
+                            {stats.expired_records} " awaiting cleanup, "
+                            {util::bytes_to_human_readable(stats.total_bytes)} " stored"
+                        </p>
                         <ul>
-                            {records.keys().map(|key| leptos::view! { cx,
+                            {records.iter().map(|(key, record)| {
+                                let last_downloaded = match record.last_downloaded {
+                                    Some(time) => format!("downloaded {}", util::relative_time(time, now)),
+                                    None => "never downloaded".to_string(),
+                                };
+                                let lifetime = format!(
+                                    "uploaded {}, expires {}",
+                                    util::relative_time(record.uploaded, now),
+                                    util::relative_time(record.expires_at(), now),
+                                );
+                                let uploader = record.uploader.clone().unwrap_or_else(|| "unknown uploader".to_string());
+                                leptos::view! { cx,
                                         <li class="link-wrapper">
-                                            <a href="/link/{key}">{key}</a>
+                                            <a href=format!("/link/{key}")>{key}</a>
+                                            <span style="margin-left: 1em;">{lifetime}</span>
+                                            <span style="margin-left: 1em;">{last_downloaded}</span>
+                                            <span style="margin-left: 1em;">{uploader}</span>
                                             <button style="margin-left: 1em;"
                                                 hx-target="closest .link-wrapper"
                                                 hx-swap="outerHTML"
-                                                hx-delete="/link/{key}">X</button>
+                                                hx-delete=format!("/records/{key}")>X</button>
                                         </li>
-                                    })
+                                    }})
                                 .collect::<Vec<_>>()}
                         </ul>
+                        <p class="link-wrapper">
+                            {(page > 1).then(|| leptos::view! { cx,
+                                <a href=format!("?page={}&per_page={per_page}", page - 1)>"prev "</a>
+                            })}
+                            "page " {page} " of " {total_pages}
+                            {(page < total_pages).then(|| leptos::view! { cx,
+                                <a href=format!("?page={}&per_page={per_page}", page + 1)>" next"</a>
+                            })}
+                        </p>
                     </div>
                 </div>
             </HtmxPage>
@@ -172,8 +487,9 @@ async fn records_links(State(state): State<AppState>) -> impl IntoResponse {
 
 async fn link(
     axum::extract::Path(id): axum::extract::Path<String>,
+    headers: HeaderMap,
     State(mut state): State<AppState>,
-) -> Result<Html<String>, Redirect> {
+) -> Result<Html<String>, axum::response::Response> {
     {
         let mut records = state.records.lock().await;
 
@@ -192,16 +508,96 @@ async fn link(
         }
     }
 
-    // TODO: This....
-    state.remove_record(&id).await.unwrap();
+    // Expired records get cleaned up here, missing ones are already as gone as can be
+    state.remove_record(&id).await.ok();
+
+    Err(not_found(&headers))
+}
+
+async fn link_qr(
+    axum::extract::Path(id): axum::extract::Path<String>,
+    Host(host): Host,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let alive = state
+        .records
+        .lock()
+        .await
+        .get(&id)
+        .is_some_and(|record| record.can_be_downloaded());
+    if !alive {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let url = format!("{}/link/{}", state.config.base_url(&host), id);
+    let svg = QrCode::new(url.as_bytes())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build();
 
-    Err(Redirect::to(&format!("/404.html")))
+    Ok(([("Content-Type", "image/svg+xml")], svg))
 }
 
+async fn link_contents(
+    axum::extract::Path(id): axum::extract::Path<String>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Result<axum::response::Response, (StatusCode, String)> {
+    let stored = state
+        .records
+        .lock()
+        .await
+        .get(&id)
+        .filter(|record| record.can_be_downloaded())
+        .map(|record| (record.format, record.file.clone()));
+
+    let Some((format, file)) = stored else {
+        return Ok(not_found(&headers));
+    };
+
+    let entries = archive::list_entries(format, &file)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+    if util::wants_html(&headers) {
+        Ok(Html(leptos::ssr::render_to_string(|cx| {
+            leptos::view! { cx, <ContentsView entries /> }
+        }))
+        .into_response())
+    } else {
+        Ok(Json(entries).into_response())
+    }
+}
+
+// Admins may delete anything, everyone else needs the upload's delete token as
+// an `X-Delete-Token` header, never in the URL where it would end up in logs
 async fn link_delete(
     axum::extract::Path(id): axum::extract::Path<String>,
+    headers: HeaderMap,
     State(mut state): State<AppState>,
 ) -> Result<Html<String>, (StatusCode, String)> {
+    let token = headers
+        .get("x-delete-token")
+        .and_then(|token| token.to_str().ok());
+
+    let owns_upload = match token {
+        Some(token) => state
+            .records
+            .lock()
+            .await
+            .get(&id)
+            .is_some_and(|record| record.delete_token_matches(token)),
+        None => false,
+    };
+
+    if !owns_upload && !auth::is_admin(&state.config, &headers) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "A valid delete token is required".to_string(),
+        ));
+    }
+
     state
         .remove_record(&id)
         .await
@@ -210,120 +606,849 @@ async fn link_delete(
     Ok(Html("".to_string()))
 }
 
-async fn log_source<B>(
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    forwarded_for: Option<TypedHeader<ForwardedFor>>,
-    req: Request<B>,
-    next: Next<B>,
-) -> impl IntoResponse {
-    tracing::info!("{} : {:?}", addr, forwarded_for);
+// Same as link_delete for admins, but routed under /records so the browser
+// already has credentials for it from the listing page
+async fn record_delete(
+    axum::extract::Path(id): axum::extract::Path<String>,
+    State(mut state): State<AppState>,
+) -> Result<Html<String>, (StatusCode, String)> {
+    state
+        .remove_record(&id)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
 
-    next.run(req).await
+    Ok(Html("".to_string()))
 }
 
-async fn upload_to_zip(
-    State(state): State<AppState>,
-    mut body: Multipart,
-) -> Result<Response<String>, (StatusCode, String)> {
-    tracing::debug!("{:?}", *state.records.lock().await);
+#[derive(Deserialize)]
+struct ExtendLink {
+    // Wider than max_downloads so anything past u8 is clamped rather than refused
+    #[serde(default)]
+    add_downloads: u64,
+    #[serde(default)]
+    extend_days: u64,
+}
 
-    let cache_name = util::get_random_name(10);
+async fn link_extend(
+    axum::extract::Path(id): axum::extract::Path<String>,
+    State(state): State<AppState>,
+    Json(extension): Json<ExtendLink>,
+) -> Result<Json<RecordListing>, (StatusCode, String)> {
+    let mut records = state.records.lock().await;
 
-    let archive_path = Path::new(".cache/serve").join(&format!("{}.zip", &cache_name));
+    let Some(record) = records.get_mut(&id) else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("No upload with the id {id:?}"),
+        ));
+    };
 
-    tracing::debug!("Zipping: {:?}", &archive_path);
+    let add_downloads = u8::try_from(extension.add_downloads).unwrap_or(u8::MAX);
+    record.max_downloads = record.max_downloads.saturating_add(add_downloads);
+    record.extend(extension.extend_days);
+    let record = record.clone();
 
-    let mut archive = tokio::fs::File::create(&archive_path)
+    let snapshot = cache::snapshot(&state.config.data_path(), &records);
+    drop(records);
+    snapshot
+        .write()
         .await
         .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
-    let mut writer = ZipFileWriter::new(&mut archive);
 
-    while let Some(field) = body.next_field().await.unwrap() {
-        let file_name = match field.file_name() {
-            Some(file_name) => sanitize(file_name),
-            _ => continue,
-        };
-
-        tracing::debug!("Downloading to Zip: {file_name:?}");
-
-        let stream = field;
-        let body_with_io_error = stream.map_err(|err| io::Error::new(io::ErrorKind::Other, err));
-        let mut body_reader = StreamReader::new(body_with_io_error);
+    Ok(Json(RecordListing { id, record }))
+}
 
-        let builder = ZipEntryBuilder::new(file_name, Compression::Deflate);
-        let mut entry_writer = writer
-            .write_entry_stream(builder)
-            .await
-            .unwrap()
-            .compat_write();
+// Runs the rest of the request in a span carrying its id, so every log line
+// for it can be found again, and hands the id back to the client
+async fn request_id<B>(req: Request<B>, next: Next<B>) -> axum::response::Response {
+    let id = req
+        .headers()
+        .get("x-request-id")
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 64 && id.bytes().all(|b| b.is_ascii_graphic()))
+        .map(str::to_owned)
+        .unwrap_or_else(|| util::get_random_name(16));
 
-        tokio::io::copy(&mut body_reader, &mut entry_writer)
-            .await
-            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let span = tracing::info_span!("request", request_id = %id);
+    let mut response = next.run(req).instrument(span).await;
 
-        entry_writer
-            .into_inner()
-            .close()
-            .await
-            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert("x-request-id", value);
     }
 
-    let mut records = state.records.lock().await;
-    let record = UploadRecord::new(archive_path);
-    records.insert(cache_name.clone(), record.clone());
+    response
+}
 
-    cache::write_to_cache(&records)
-        .await
-        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+async fn log_source<B>(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request<B>,
+    next: Next<B>,
+) -> impl IntoResponse {
+    let client_ip =
+        nyazoom_headers::resolve_client_ip(req.headers(), addr.ip(), &state.config.trusted_proxies);
+    tracing::info!(peer = %addr, client_ip = %client_ip, "{} {}", req.method(), req.uri());
 
-    writer.close().await.unwrap();
+    next.run(req).await
+}
+
+async fn upload_to_zip(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Multipart,
+) -> Result<Response<String>, (StatusCode, String)> {
+    let client_ip =
+        nyazoom_headers::resolve_client_ip(&headers, addr.ip(), &state.config.trusted_proxies);
+    let StoredUpload {
+        id,
+        record,
+        delete_token,
+    } = upload::archive_upload(&state, client_ip, body).await?;
 
-    let id = cache_name;
     let response = Response::builder()
         .status(200)
         .header("Content-Type", "text/html")
         .header("HX-Push-Url", format!("/link/{}", &id))
         .body(leptos::ssr::render_to_string(|cx| {
-            leptos::view! { cx, <LinkView id record /> }
+            leptos::view! { cx, <LinkView id record delete_token /> }
         }))
         .unwrap();
 
     Ok(response)
 }
 
+async fn api_upload(
+    State(state): State<AppState>,
+    Host(host): Host,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Multipart,
+) -> Result<Json<UploadResponse>, (StatusCode, String)> {
+    let client_ip =
+        nyazoom_headers::resolve_client_ip(&headers, addr.ip(), &state.config.trusted_proxies);
+    let upload = upload::archive_upload(&state, client_ip, body).await?;
+
+    Ok(Json(UploadResponse::new(
+        upload,
+        &state.config.base_url(&host),
+    )))
+}
+
+// Archives never change once written, but each one belongs to whoever has the link
+const ARCHIVE_CACHE_CONTROL: &str = "private, immutable";
+
 async fn download(
     axum::extract::Path(id): axum::extract::Path<String>,
     headers: HeaderMap,
     State(state): State<AppState>,
 ) -> Result<axum::response::Response, (StatusCode, String)> {
-    {
+    if headers.get("hx-request").is_some() {
+        return Ok(axum::http::Response::builder()
+            .header("HX-Redirect", format!("/download/{id}"))
+            .status(204)
+            .body("".to_owned())
+            .unwrap()
+            .into_response());
+    }
+
+    let (file, format, size, etag, snapshot) = {
         let mut records = state.records.lock().await;
-        if headers.get("hx-request").is_some() {
-            return Ok(axum::http::Response::builder()
-                .header("HX-Redirect", format!("/download/{id}"))
-                .status(204)
-                .body("".to_owned())
-                .unwrap()
-                .into_response());
+        let now = Utc::now();
+
+        match records.get_mut(&id) {
+            // The client already has the file, so this doesn't use up a download
+            Some(record)
+                if record.can_be_downloaded_at(now)
+                    && util::etag_matches(&headers, &record.etag()) =>
+            {
+                return Ok((
+                    StatusCode::NOT_MODIFIED,
+                    [
+                        ("ETag", record.etag()),
+                        ("Cache-Control", ARCHIVE_CACHE_CONTROL.to_string()),
+                    ],
+                )
+                    .into_response());
+            }
+            // Checking and claiming the download under one lock means concurrent
+            // requests can never share the last remaining download
+            Some(record) if record.can_be_downloaded_at(now) => {
+                record.downloads += 1;
+                record.last_downloaded = Some(now);
+                let format = record.format;
+                let size = record.size;
+                let burn = record.burn;
+                let etag = record.etag();
+
+                let file = tokio::fs::File::open(&record.file)
+                    .await
+                    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+                if burn {
+                    // The open handle keeps reading after the file is unlinked, so
+                    // the upload is gone for good before the first byte goes out
+                    let freed = records
+                        .remove_record(&id)
+                        .await
+                        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+                    state.release_stored(freed);
+                }
+
+                let snapshot = cache::snapshot(&state.config.data_path(), &records);
+                (file, format, size, etag, snapshot)
+            }
+            _ => {
+                // Expired records get cleaned up here, missing ones have nothing to clean
+                if let Ok(freed) = records.remove_record(&id).await {
+                    state.release_stored(freed);
+                    let snapshot = cache::snapshot(&state.config.data_path(), &records);
+                    drop(records);
+                    snapshot.write().await.ok();
+                }
+                return Ok(not_found(&headers));
+            }
         }
+    };
 
-        if let Some(record) = records
+    snapshot
+        .write()
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+    Metrics::inc(&state.metrics.downloads_total);
+
+    let mut response = axum::response::Response::builder()
+        .header("Content-Type", format.content_type())
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}.{}\"", id, format.extension()),
+        )
+        .header("ETag", etag)
+        .header("Cache-Control", ARCHIVE_CACHE_CONTROL);
+    // Records from before sizes were kept don't know theirs, those go out chunked
+    if size > 0 {
+        response = response.header("Content-Length", size);
+    }
+
+    Ok(response
+        .body(StreamBody::new(ReaderStream::new(file)))
+        .unwrap()
+        .into_response())
+}
+
+async fn download_entry(
+    axum::extract::Path((id, name)): axum::extract::Path<(String, String)>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Result<axum::response::Response, (StatusCode, String)> {
+    let (format, file) = {
+        let mut records = state.records.lock().await;
+        match records.get(&id) {
+            Some(record) if record.can_be_downloaded() => (record.format, record.file.clone()),
+            _ => {
+                // Expired records get cleaned up here, missing ones have nothing to clean
+                if let Ok(freed) = records.remove_record(&id).await {
+                    state.release_stored(freed);
+                    let snapshot = cache::snapshot(&state.config.data_path(), &records);
+                    drop(records);
+                    snapshot.write().await.ok();
+                }
+                return Ok(not_found(&headers));
+            }
+        }
+    };
+
+    // Finding the entry can mean reading through the whole archive, so other
+    // requests aren't held up behind it
+    let opened = match archive::open_entry(format, &file, &name).await {
+        Ok(opened) => opened,
+        // Burned or culled in the meantime
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+    let Some((entry, reader)) = opened else {
+        return Ok(not_found(&headers));
+    };
+
+    let snapshot = {
+        let mut records = state.records.lock().await;
+        let now = Utc::now();
+
+        // The record may have run out, or been burned, while the entry was opened
+        let Some(record) = records
             .get_mut(&id)
-            .filter(|record| record.can_be_downloaded())
-        {
+            .filter(|record| record.file == file && record.can_be_downloaded_at(now))
+        else {
+            return Ok(not_found(&headers));
+        };
+
+        record.last_downloaded = Some(now);
+        // A burn link is gone after any download, one file is no exception
+        if state.config.count_entry_downloads || record.burn {
             record.downloads += 1;
+        }
+
+        if record.burn {
+            // Same as a full download, the open reader outlives the file
+            let freed = records
+                .remove_record(&id)
+                .await
+                .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+            state.release_stored(freed);
+        }
+
+        cache::snapshot(&state.config.data_path(), &records)
+    };
 
-            let file = tokio::fs::File::open(&record.file).await.unwrap();
+    snapshot
+        .write()
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+    Metrics::inc(&state.metrics.downloads_total);
+
+    let content_type = mime_guess::from_path(&entry.name).first_or_octet_stream();
+
+    Ok(axum::response::Response::builder()
+        .header("Content-Type", content_type.as_ref())
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", entry.name.replace('"', "'")),
+        )
+        .header("Content-Length", entry.size)
+        .body(StreamBody::new(ReaderStream::new(reader)))
+        .unwrap()
+        .into_response())
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: &'static str,
+}
+
+fn not_found(headers: &HeaderMap) -> axum::response::Response {
+    if util::wants_html(headers) {
+        (
+            StatusCode::NOT_FOUND,
+            Html(leptos::ssr::render_to_string(|cx| {
+                leptos::view! { cx, <NotFoundPage /> }
+            })),
+        )
+            .into_response()
+    } else {
+        (
+            StatusCode::NOT_FOUND,
+            Json(ErrorBody { error: "not found" }),
+        )
+            .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::{body::Body, http::header};
+
+    use crate::test_util;
 
-            return Ok(axum::response::Response::builder()
-                .header("Content-Type", "application/zip")
-                .body(StreamBody::new(ReaderStream::new(file)))
-                .unwrap()
-                .into_response());
-        } else {
-            records.remove_record(&id).await.unwrap()
+    #[tokio::test]
+    async fn metrics_count_uploads() {
+        let state = test_util::state().await;
+        let form = test_util::Form::new().file("cat.txt", b"meow");
+        let (_, record) = test_util::upload(&state, form).await;
+
+        let response = test_util::send(&state, test_util::get("/metrics")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = test_util::body_text(response).await;
+        assert!(body.lines().any(|line| line == "nyazoom_uploads_total 1"));
+        assert!(body.lines().any(|line| line == "nyazoom_active_records 1"));
+        let bytes_stored = format!("nyazoom_bytes_stored {}", record.size);
+        assert!(body.lines().any(|line| line == bytes_stored));
+    }
+
+    #[tokio::test]
+    async fn not_found_speaks_json_by_default() {
+        let state = test_util::state().await;
+
+        let response = test_util::send(&state, test_util::get("/link/missing")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            test_util::body_json(response).await,
+            serde_json::json!({ "error": "not found" })
+        );
+    }
+
+    #[tokio::test]
+    async fn not_found_renders_a_page_for_browsers() {
+        let state = test_util::state().await;
+
+        let request = Request::get("/link/missing")
+            .header(header::ACCEPT, "text/html,application/xhtml+xml")
+            .body(Body::empty())
+            .unwrap();
+        let response = test_util::send(&state, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(test_util::body_text(response)
+            .await
+            .contains("Link not found"));
+    }
+
+    #[tokio::test]
+    async fn expired_links_render_the_not_found_page() {
+        let state = test_util::state().await;
+        let record = state::UploadRecord {
+            downloads: 1,
+            max_downloads: 1,
+            ..Default::default()
+        };
+        test_util::insert_upload(&state, "cat", &[("cat.txt", "meow")], record).await;
+
+        let request = Request::get("/link/cat")
+            .header(header::ACCEPT, "text/html")
+            .body(Body::empty())
+            .unwrap();
+        let response = test_util::send(&state, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(test_util::body_text(response)
+            .await
+            .contains("Link not found"));
+        assert!(state.records.lock().await.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn last_download_goes_to_one_request() {
+        let state = test_util::state().await;
+        let record = state::UploadRecord {
+            max_downloads: 1,
+            ..Default::default()
+        };
+        test_util::insert_upload(&state, "cat", &[("cat.txt", "meow")], record).await;
+
+        let requests: Vec<_> = (0..2)
+            .map(|_| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    test_util::send(&state, test_util::get("/download/cat"))
+                        .await
+                        .status()
+                })
+            })
+            .collect();
+
+        let mut statuses = Vec::new();
+        for request in requests {
+            statuses.push(request.await.unwrap());
         }
+        statuses.sort();
+        assert_eq!(statuses, [StatusCode::OK, StatusCode::NOT_FOUND]);
     }
 
-    Ok(Redirect::to("/404.html").into_response())
+    #[tokio::test]
+    async fn welcome_never_waits_on_a_cat_fact() {
+        // No refresh runs outside of main, same as a fetch that's still stalled
+        let state = test_util::state().await;
+
+        let response = test_util::send(&state, test_util::get("/")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(test_util::body_text(response)
+            .await
+            .contains("The cat fact goddess has failed me"));
+
+        *state.cat_fact.write().await = Some("Cats sleep a lot".to_owned());
+        let response = test_util::send(&state, test_util::get("/")).await;
+        assert!(test_util::body_text(response)
+            .await
+            .contains("Cats sleep a lot"));
+    }
+
+    fn accepting(uri: &str, accept: &str) -> Request<Body> {
+        Request::get(uri)
+            .header(header::ACCEPT, accept)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn remaining_as_json() {
+        let state = test_util::state().await;
+        let record = state::UploadRecord {
+            downloads: 3,
+            max_downloads: 5,
+            ..Default::default()
+        };
+        let expires_at = record.expires_at();
+        state.records.lock().await.insert("cat".to_owned(), record);
+
+        let response =
+            test_util::send(&state, accepting("/link/cat/remaining", "application/json")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = test_util::body_json(response).await;
+        assert_eq!(body["remaining"], 2);
+        assert_eq!(body["expires_at"], serde_json::json!(expires_at));
+
+        let response =
+            test_util::send(&state, accepting("/link/dog/remaining", "application/json")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn remaining_as_html() {
+        let state = test_util::state().await;
+        let record = state::UploadRecord {
+            downloads: 4,
+            max_downloads: 5,
+            ..Default::default()
+        };
+        state.records.lock().await.insert("cat".to_owned(), record);
+
+        let response = test_util::send(&state, test_util::get("/link/cat/remaining")).await;
+        assert_eq!(
+            test_util::body_text(response).await,
+            "You have 1 download remaining!"
+        );
+
+        let response = test_util::send(&state, test_util::get("/link/dog/remaining")).await;
+        assert_eq!(test_util::body_text(response).await, "?");
+    }
+
+    fn delete(uri: &str) -> Request<Body> {
+        Request::delete(uri).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn delete_tokens_only_come_from_the_header() {
+        let state = test_util::state().await;
+        let record = state::UploadRecord {
+            delete_token_hash: Some(auth::hash_token("secret")),
+            ..Default::default()
+        };
+        test_util::insert_upload(&state, "cat", &[("cat.txt", "meow")], record).await;
+
+        let response = test_util::send(&state, delete("/link/cat?token=secret")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let mut request = delete("/link/cat");
+        request
+            .headers_mut()
+            .insert("x-delete-token", "secret".parse().unwrap());
+        let response = test_util::send(&state, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(state.records.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn admins_delete_from_the_listing() {
+        let state = test_util::state().await;
+        test_util::insert_upload(&state, "cat", &[("cat.txt", "meow")], Default::default()).await;
+
+        let listing = test_util::send(
+            &state,
+            test_util::as_admin(test_util::get("/records/links")),
+        )
+        .await;
+        assert!(test_util::body_text(listing)
+            .await
+            .contains(r#"hx-delete="/records/cat""#));
+
+        let response = test_util::send(&state, delete("/records/cat")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = test_util::send(&state, test_util::as_admin(delete("/records/cat"))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(state.records.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn contents_lists_every_file() {
+        let state = test_util::state().await;
+        let files = [("cat.txt", "meow"), ("dog.txt", "woof woof")];
+        test_util::insert_upload(&state, "pets", &files, Default::default()).await;
+
+        let response = test_util::send(&state, test_util::get("/link/pets/contents")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            test_util::body_json(response).await,
+            serde_json::json!([
+                { "name": "cat.txt", "size": 4 },
+                { "name": "dog.txt", "size": 9 },
+            ])
+        );
+    }
+
+    fn pagination(page: usize, per_page: usize) -> Pagination {
+        Pagination {
+            page: Some(page),
+            per_page: Some(per_page),
+        }
+    }
+
+    #[test]
+    fn pages_split_at_per_page() {
+        let items: Vec<_> = (0..5).collect();
+
+        assert_eq!(pagination(1, 2).apply(items.clone()), [0, 1]);
+        assert_eq!(pagination(2, 2).apply(items.clone()), [2, 3]);
+        assert_eq!(pagination(3, 2).apply(items.clone()), [4]);
+        assert_eq!(pagination(1, 2).total_pages(items.len()), 3);
+        assert_eq!(pagination(1, 5).total_pages(items.len()), 1);
+    }
+
+    #[test]
+    fn out_of_range_pages() {
+        let items: Vec<_> = (0..5).collect();
+
+        // Past the end is empty, before the start is the first page
+        assert!(pagination(4, 2).apply(items.clone()).is_empty());
+        assert!(pagination(usize::MAX, 2).apply(items.clone()).is_empty());
+        assert_eq!(pagination(0, 2).apply(items.clone()), [0, 1]);
+
+        assert_eq!(pagination(1, 0).per_page(), 1);
+        assert_eq!(pagination(1, usize::MAX).per_page(), MAX_PER_PAGE);
+        assert_eq!(pagination(1, 2).total_pages(0), 1);
+    }
+
+    #[tokio::test]
+    async fn burn_links_only_download_once() {
+        let state = test_util::state().await;
+        let record = state::UploadRecord {
+            burn: true,
+            ..Default::default()
+        };
+        let record = test_util::insert_upload(&state, "cat", &[("cat.txt", "meow")], record).await;
+
+        let response = test_util::send(&state, test_util::get("/download/cat")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        // The open file keeps streaming even though it's already been deleted
+        let body = test_util::body_bytes(response).await;
+        assert_eq!(body.len() as u64, record.size);
+
+        let response = test_util::send(&state, test_util::get("/download/cat")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(!record.file.exists());
+    }
+
+    #[tokio::test]
+    async fn extending_keeps_a_link_alive() {
+        let state = test_util::state().await;
+        // A minute from expiring, and out of downloads already
+        let lifetime = chrono::Duration::from_std(state::UploadRecord::DEFAULT_LIFETIME).unwrap();
+        let record = state::UploadRecord {
+            uploaded: Utc::now() - lifetime + chrono::Duration::minutes(1),
+            downloads: 1,
+            max_downloads: 1,
+            ..Default::default()
+        };
+        let record = test_util::insert_upload(&state, "cat", &[("cat.txt", "meow")], record).await;
+        let expired_at = record.expires_at();
+
+        let request = Request::post("/link/cat/extend")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"add_downloads": 1, "extend_days": 1}"#))
+            .unwrap();
+        let response = test_util::send(&state, test_util::as_admin(request)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let extended = state.records.lock().await["cat"].clone();
+        assert_eq!(
+            extended.expires_at(),
+            expired_at + chrono::Duration::days(1)
+        );
+        assert!(extended.can_be_downloaded_at(expired_at + chrono::Duration::hours(1)));
+
+        let response = test_util::send(&state, test_util::get("/download/cat")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn extending_clamps_downloads() {
+        let state = test_util::state().await;
+        let record = state::UploadRecord {
+            max_downloads: 10,
+            ..Default::default()
+        };
+        test_util::insert_upload(&state, "cat", &[("cat.txt", "meow")], record).await;
+
+        let request = Request::post("/link/cat/extend")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"add_downloads": 300}"#))
+            .unwrap();
+        let response = test_util::send(&state, test_util::as_admin(request)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(state.records.lock().await["cat"].max_downloads, u8::MAX);
+    }
+
+    fn response_id(response: &axum::response::Response) -> &str {
+        response.headers()["x-request-id"]
+            .to_str()
+            .expect("request ids are ascii")
+    }
+
+    #[tokio::test]
+    async fn responses_carry_a_request_id() {
+        let state = test_util::state().await;
+
+        let response = test_util::send(&state, test_util::get("/")).await;
+        assert_eq!(response_id(&response).len(), 16);
+
+        let request = Request::get("/")
+            .header("x-request-id", "cat-1234")
+            .body(Body::empty())
+            .unwrap();
+        let response = test_util::send(&state, request).await;
+        assert_eq!(response_id(&response), "cat-1234");
+
+        // Ids that would mangle the logs get replaced rather than echoed
+        let request = Request::get("/")
+            .header("x-request-id", "cat 1234")
+            .body(Body::empty())
+            .unwrap();
+        let response = test_util::send(&state, request).await;
+        assert_ne!(response_id(&response), "cat 1234");
+    }
+
+    #[tokio::test]
+    async fn single_files_come_out_of_an_upload() {
+        let state = test_util::state().await;
+        let files = [("cat.txt", "meow"), ("dog.txt", "woof woof")];
+        test_util::insert_upload(&state, "pets", &files, Default::default()).await;
+
+        let response =
+            test_util::send(&state, test_util::get("/download/pets/entry/dog.txt")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "9");
+        assert_eq!(test_util::body_bytes(response).await, "woof woof");
+        assert_eq!(state.records.lock().await["pets"].downloads, 1);
+
+        let response =
+            test_util::send(&state, test_util::get("/download/pets/entry/fish.txt")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn single_files_still_burn_links() {
+        let state = test_util::state_with(Config {
+            count_entry_downloads: false,
+            ..test_util::config()
+        })
+        .await;
+        let files = [("cat.txt", "meow"), ("dog.txt", "woof woof")];
+        let record = state::UploadRecord {
+            burn: true,
+            ..Default::default()
+        };
+        test_util::insert_upload(&state, "pets", &files, record).await;
+
+        let response =
+            test_util::send(&state, test_util::get("/download/pets/entry/cat.txt")).await;
+        assert_eq!(test_util::body_bytes(response).await, "meow");
+        assert!(state.records.lock().await.is_empty());
+
+        let response =
+            test_util::send(&state, test_util::get("/download/pets/entry/dog.txt")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn downloads_know_their_length() {
+        let state = test_util::state().await;
+        let record =
+            test_util::insert_upload(&state, "cat", &[("cat.txt", "meow")], Default::default())
+                .await;
+
+        let response = test_util::send(&state, test_util::get("/download/cat")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_LENGTH],
+            record.size.to_string()
+        );
+        assert_eq!(
+            test_util::body_bytes(response).await.len() as u64,
+            record.size
+        );
+    }
+
+    #[tokio::test]
+    async fn admins_cull_on_demand() {
+        let state = test_util::state().await;
+        let spent = state::UploadRecord {
+            downloads: 1,
+            max_downloads: 1,
+            ..Default::default()
+        };
+        let spent = test_util::insert_upload(&state, "spent", &[("cat.txt", "meow")], spent).await;
+        test_util::insert_upload(&state, "live", &[("dog.txt", "woof")], Default::default()).await;
+
+        let cull = || Request::post("/admin/cull").body(Body::empty()).unwrap();
+        let response = test_util::send(&state, cull()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(state.records.lock().await.len(), 2);
+
+        let response = test_util::send(&state, test_util::as_admin(cull())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            test_util::body_json(response).await,
+            serde_json::json!({ "removed": 1, "bytes_freed": spent.size })
+        );
+        let records = state.records.lock().await;
+        assert!(records.contains_key("live") && !records.contains_key("spent"));
+    }
+
+    #[tokio::test]
+    async fn revalidating_keeps_the_download() {
+        let state = test_util::state().await;
+        let record = state::UploadRecord {
+            max_downloads: 2,
+            ..Default::default()
+        };
+        test_util::insert_upload(&state, "cat", &[("cat.txt", "meow")], record).await;
+
+        let response = test_util::send(&state, test_util::get("/download/cat")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[header::ETAG].clone();
+
+        let request = Request::get("/download/cat")
+            .header(header::IF_NONE_MATCH, etag.clone())
+            .body(Body::empty())
+            .unwrap();
+        let response = test_util::send(&state, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag);
+        assert!(test_util::body_bytes(response).await.is_empty());
+
+        assert_eq!(state.records.lock().await["cat"].downloads, 1);
+    }
+
+    fn batch_delete(uri: &str, ids: &str) -> Request<Body> {
+        test_util::as_admin(
+            Request::post(uri)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(ids.to_owned()))
+                .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn batches_delete_what_they_can() {
+        let state = test_util::state().await;
+        test_util::insert_upload(&state, "cat", &[("cat.txt", "meow")], Default::default()).await;
+        test_util::insert_upload(&state, "dog", &[("dog.txt", "woof")], Default::default()).await;
+        let ids = r#"["cat", "fish", "dog"]"#;
+
+        // A dry run only reports
+        let response =
+            test_util::send(&state, batch_delete("/admin/delete?dry_run=true", ids)).await;
+        let body = test_util::body_json(response).await;
+        assert_eq!(body["removed"], serde_json::json!(["cat", "dog"]));
+        assert_eq!(state.records.lock().await.len(), 2);
+
+        let response = test_util::send(&state, batch_delete("/admin/delete", ids)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = test_util::body_json(response).await;
+        assert_eq!(body["removed"], serde_json::json!(["cat", "dog"]));
+        assert_eq!(body["failed"].as_array().unwrap().len(), 1);
+        assert_eq!(body["failed"][0]["id"], "fish");
+        assert!(state.records.lock().await.is_empty());
+    }
 }
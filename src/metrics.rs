@@ -0,0 +1,76 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub uploads_total: AtomicU64,
+    pub downloads_total: AtomicU64,
+    pub records_culled_total: AtomicU64,
+}
+
+impl Metrics {
+    #[inline]
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Renders every metric in the Prometheus text exposition format, gauges are
+    /// measured by the caller at scrape time
+    pub fn render(&self, active_records: usize, bytes_stored: u64) -> String {
+        let mut out = String::new();
+
+        let counters = [
+            (
+                "nyazoom_uploads_total",
+                "Archives created from uploads",
+                &self.uploads_total,
+            ),
+            (
+                "nyazoom_downloads_total",
+                "Archives served for download",
+                &self.downloads_total,
+            ),
+            (
+                "nyazoom_records_culled_total",
+                "Expired records removed by the cleaning sweep",
+                &self.records_culled_total,
+            ),
+        ];
+
+        for (name, help, counter) in counters {
+            write_metric(
+                &mut out,
+                name,
+                help,
+                "counter",
+                counter.load(Ordering::Relaxed),
+            );
+        }
+
+        write_metric(
+            &mut out,
+            "nyazoom_active_records",
+            "Upload records currently held",
+            "gauge",
+            active_records as u64,
+        );
+        write_metric(
+            &mut out,
+            "nyazoom_bytes_stored",
+            "Bytes of archives currently on disk",
+            "gauge",
+            bytes_stored,
+        );
+
+        out
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, help: &str, kind: &str, value: u64) {
+    // Writing into a String can't fail
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    let _ = writeln!(out, "{name} {value}");
+}
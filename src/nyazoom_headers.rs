@@ -1,4 +1,6 @@
-use headers::{self, Header, HeaderName, HeaderValue};
+use headers::{self, Header, HeaderMap, HeaderMapExt, HeaderName, HeaderValue};
+
+use std::net::IpAddr;
 
 #[derive(Debug)]
 pub struct ForwardedFor(String);
@@ -7,6 +9,9 @@ pub static FF_TEXT: &str = "x-forwarded-for";
 
 pub static FF_NAME: HeaderName = HeaderName::from_static(FF_TEXT);
 
+// Generous for any realistic chain of proxies, while keeping junk out of the logs
+pub const FF_MAX_LEN: usize = 1024;
+
 impl Header for ForwardedFor {
     fn name() -> &'static HeaderName {
         &FF_NAME
@@ -24,10 +29,157 @@ impl Header for ForwardedFor {
             .map_err(|_| headers::Error::invalid())?
             .to_owned();
 
+        // Only printable ascii, this ends up in the logs verbatim
+        if value.len() > FF_MAX_LEN || !value.bytes().all(|b| (0x20..=0x7e).contains(&b)) {
+            return Err(headers::Error::invalid());
+        }
+
         Ok(ForwardedFor(value))
     }
 
     fn encode<E: Extend<headers::HeaderValue>>(&self, values: &mut E) {
-        values.extend(std::iter::once(HeaderValue::from_str(&self.0).unwrap()));
+        values.extend(HeaderValue::from_str(&self.0).ok());
+    }
+}
+
+impl ForwardedFor {
+    /// The left-most entry of the header, which is the original client as
+    /// reported by the first proxy in the chain
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.0.split(',').next()?.trim().parse().ok()
+    }
+}
+
+#[derive(Debug)]
+pub struct RealIp(IpAddr);
+
+pub static RI_TEXT: &str = "x-real-ip";
+
+pub static RI_NAME: HeaderName = HeaderName::from_static(RI_TEXT);
+
+impl Header for RealIp {
+    fn name() -> &'static HeaderName {
+        &RI_NAME
+    }
+
+    fn decode<'i, I>(values: &mut I) -> Result<Self, headers::Error>
+    where
+        Self: Sized,
+        I: Iterator<Item = &'i headers::HeaderValue>,
+    {
+        let ip = values
+            .next()
+            .ok_or_else(headers::Error::invalid)?
+            .to_str()
+            .map_err(|_| headers::Error::invalid())?
+            .trim()
+            .parse()
+            .map_err(|_| headers::Error::invalid())?;
+
+        Ok(RealIp(ip))
+    }
+
+    fn encode<E: Extend<headers::HeaderValue>>(&self, values: &mut E) {
+        values.extend(std::iter::once(HeaderValue::from(self.0.to_string())));
+    }
+}
+
+/// Works out who is really on the other end. When the peer is one of
+/// `trusted_proxies` that's the left-most `X-Forwarded-For` entry, then
+/// `X-Real-IP`, otherwise anyone could claim any address so it's the peer itself.
+pub fn resolve_client_ip(headers: &HeaderMap, peer: IpAddr, trusted_proxies: &[IpAddr]) -> IpAddr {
+    if !trusted_proxies.contains(&peer) {
+        return peer;
+    }
+
+    headers
+        .typed_get::<ForwardedFor>()
+        .and_then(|forwarded_for| forwarded_for.client_ip())
+        .or_else(|| headers.typed_get::<RealIp>().map(|RealIp(ip)| ip))
+        .unwrap_or(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_static(name),
+                    HeaderValue::from_static(value),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn forwarded_for_wins_from_trusted_proxies() {
+        let headers = headers(&[
+            (FF_TEXT, "203.0.113.7, 10.0.0.2"),
+            (RI_TEXT, "198.51.100.1"),
+        ]);
+        let proxy = ip("10.0.0.1");
+
+        assert_eq!(
+            resolve_client_ip(&headers, proxy, &[proxy]),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn real_ip_is_next_from_trusted_proxies() {
+        let proxy = ip("10.0.0.1");
+
+        let real_ip_only = headers(&[(RI_TEXT, "198.51.100.1")]);
+        assert_eq!(
+            resolve_client_ip(&real_ip_only, proxy, &[proxy]),
+            ip("198.51.100.1")
+        );
+
+        // An unparseable X-Forwarded-For falls through rather than winning
+        let garbled = headers(&[(FF_TEXT, "not an address"), (RI_TEXT, "198.51.100.1")]);
+        assert_eq!(
+            resolve_client_ip(&garbled, proxy, &[proxy]),
+            ip("198.51.100.1")
+        );
+    }
+
+    #[test]
+    fn peer_is_last_resort() {
+        let proxy = ip("10.0.0.1");
+        assert_eq!(resolve_client_ip(&HeaderMap::new(), proxy, &[proxy]), proxy);
+    }
+
+    #[test]
+    fn untrusted_peers_cant_spoof() {
+        let headers = headers(&[(FF_TEXT, "203.0.113.7"), (RI_TEXT, "198.51.100.1")]);
+        let peer = ip("192.0.2.50");
+
+        assert_eq!(resolve_client_ip(&headers, peer, &[]), peer);
+        assert_eq!(resolve_client_ip(&headers, peer, &[ip("10.0.0.1")]), peer);
+    }
+
+    #[test]
+    fn forwarded_for_rejects_junk() {
+        let mut headers = HeaderMap::new();
+
+        let overlong = vec!["203.0.113.7"; 200].join(", ");
+        assert!(overlong.len() > FF_MAX_LEN);
+        headers.insert(FF_TEXT, HeaderValue::from_str(&overlong).unwrap());
+        assert!(headers.typed_get::<ForwardedFor>().is_none());
+
+        headers.insert(FF_TEXT, HeaderValue::from_static("203.0.113.7,\t10.0.0.2"));
+        assert!(headers.typed_get::<ForwardedFor>().is_none());
+
+        headers.insert(FF_TEXT, HeaderValue::from_static("203.0.113.7, 10.0.0.2"));
+        let forwarded_for = headers.typed_get::<ForwardedFor>().unwrap();
+        assert_eq!(forwarded_for.client_ip(), Some(ip("203.0.113.7")));
     }
 }
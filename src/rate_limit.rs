@@ -0,0 +1,85 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio::sync::Mutex;
+
+use crate::{nyazoom_headers, state::AppState};
+
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    hits: Arc<Mutex<HashMap<IpAddr, VecDeque<Instant>>>>,
+}
+
+impl RateLimiter {
+    /// Records a hit for `ip`, or returns how long until it may try again if it
+    /// has already used up `limit` hits within `window`
+    pub async fn check(&self, ip: IpAddr, limit: usize, window: Duration) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut hits = self.hits.lock().await;
+
+        // Forget everything that has left the window so idle clients don't pile up
+        hits.retain(|_, times| {
+            while times
+                .front()
+                .is_some_and(|time| now.duration_since(*time) >= window)
+            {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+
+        let times = hits.entry(ip).or_default();
+        if times.len() >= limit {
+            let oldest = *times.front().unwrap();
+            return Err(window - now.duration_since(oldest));
+        }
+
+        times.push_back(now);
+        Ok(())
+    }
+}
+
+pub async fn limit_uploads<B>(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let limit = state.config.upload_limit;
+    if limit == 0 {
+        return next.run(req).await;
+    }
+
+    let ip =
+        nyazoom_headers::resolve_client_ip(req.headers(), addr.ip(), &state.config.trusted_proxies);
+
+    match state
+        .upload_limiter
+        .check(ip, limit, state.config.upload_window)
+        .await
+    {
+        Ok(()) => next.run(req).await,
+        Err(retry_after) => {
+            tracing::info!("rate limiting uploads from {}", ip);
+
+            // Round up so clients never retry a moment too early
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, secs.to_string())],
+                "Too many uploads, please try again later",
+            )
+                .into_response()
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use async_trait::async_trait;
+
+use axum::{body::Bytes, http::StatusCode};
+
+use reqwest::{header, redirect, Response, Url};
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use tokio_util::io::StreamReader;
+
+use crate::archive::EntryReader;
+
+// Servers get this long to answer, and then this long between each read of
+// the body, a total timeout would cut off large downloads that are fine
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where files named by an upload's `url` fields come from
+#[async_trait]
+pub trait Source: Send + Sync {
+    /// Starts downloading `url`, returning a name for the file along with its body
+    async fn fetch(&self, url: &str) -> Result<(String, EntryReader), (StatusCode, String)>;
+}
+
+/// Fetches over http and https, from public addresses only
+pub struct Http;
+
+#[async_trait]
+impl Source for Http {
+    async fn fetch(&self, url: &str) -> Result<(String, EntryReader), (StatusCode, String)> {
+        fetch(url).await
+    }
+}
+
+async fn fetch(url: &str) -> Result<(String, EntryReader), (StatusCode, String)> {
+    let url = Url::parse(url.trim())
+        .map_err(|err| (StatusCode::BAD_REQUEST, format!("Invalid url: {err}")))?;
+
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Only http and https urls can be fetched".to_string(),
+        ));
+    }
+
+    // IPv6 hosts keep their brackets in urls, but not when being resolved
+    let host = url
+        .host_str()
+        .map(|host| {
+            host.trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_owned()
+        })
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, "The url has no host".to_string()))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let addr = tokio::net::lookup_host((host.as_str(), port))
+        .await
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Could not resolve {host:?}"),
+            )
+        })?;
+
+    if !is_public(addr.ip()) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Urls pointing at private addresses can't be fetched".to_string(),
+        ));
+    }
+
+    let request = pinned_client(&host, addr)?.get(url.clone()).send();
+    let response = tokio::time::timeout(RESPONSE_TIMEOUT, request)
+        .await
+        .map_err(|_| {
+            (
+                StatusCode::GATEWAY_TIMEOUT,
+                "The url took too long to respond".to_string(),
+            )
+        })?
+        .map_err(|err| (StatusCode::BAD_GATEWAY, err.to_string()))?;
+
+    if !response.status().is_success() {
+        return Err((
+            StatusCode::BAD_GATEWAY,
+            format!("Fetching the url failed with {}", response.status()),
+        ));
+    }
+
+    let name = disposition_name(response.headers())
+        .or_else(|| {
+            url.path_segments()
+                .and_then(|mut segments| segments.next_back())
+                .filter(|segment| !segment.is_empty())
+                .map(str::to_owned)
+        })
+        .unwrap_or(host);
+
+    Ok((name, Box::pin(StreamReader::new(body_stream(response)))))
+}
+
+// The body as a stream that errors out once the server goes quiet for too long
+fn body_stream(response: Response) -> impl futures::Stream<Item = io::Result<Bytes>> + Send {
+    futures::stream::unfold(Some(response), |response| async move {
+        let mut response = response?;
+        match tokio::time::timeout(READ_TIMEOUT, response.chunk()).await {
+            Ok(Ok(Some(chunk))) => Some((Ok(chunk), Some(response))),
+            Ok(Ok(None)) => None,
+            // Nothing more is read after an error
+            Ok(Err(err)) => Some((Err(io::Error::new(io::ErrorKind::Other, err)), None)),
+            Err(_) => Some((
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the url stopped sending data",
+                )),
+                None,
+            )),
+        }
+    })
+}
+
+// The client only ever connects to the address that was checked, so the name
+// can't be re-pointed somewhere private in between. Redirects aren't followed
+// for the same reason.
+fn pinned_client(host: &str, addr: SocketAddr) -> Result<reqwest::Client, (StatusCode, String)> {
+    reqwest::Client::builder()
+        .resolve(host, addr)
+        .redirect(redirect::Policy::none())
+        .connect_timeout(Duration::from_secs(10))
+        .build()
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+fn disposition_name(headers: &header::HeaderMap) -> Option<String> {
+    headers
+        .get(header::CONTENT_DISPOSITION)?
+        .to_str()
+        .ok()?
+        .split(';')
+        .find_map(|param| param.trim().strip_prefix("filename="))
+        .map(|name| name.trim_matches('"').to_owned())
+        .filter(|name| !name.is_empty())
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            // 100.64.0.0/10 is carrier grade nat space, and 0.0.0.0/8 reaches
+            // this host on some systems
+            let shared = a == 100 && (b & 0xc0) == 64;
+            let this_network = a == 0;
+
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || shared
+                || this_network)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let segments = ip.segments();
+                let first = segments[0];
+                // fc00::/7 is unique local and fe80::/10 link local
+                let unique_local = (first & 0xfe00) == 0xfc00;
+                let link_local = (first & 0xffc0) == 0xfe80;
+                // 64:ff9b::/96 translates to any ipv4 address, private ones included
+                let nat64 = segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0];
+
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || unique_local
+                    || link_local
+                    || nat64)
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_public_addresses_are_fetched() {
+        let cases = [
+            ("127.0.0.1", false),
+            ("::1", false),
+            ("10.1.2.3", false),
+            ("172.16.0.1", false),
+            ("192.168.1.1", false),
+            ("169.254.169.254", false),
+            ("fe80::1", false),
+            ("fd00::1", false),
+            ("100.64.0.1", false),
+            ("100.127.255.255", false),
+            ("0.0.0.0", false),
+            ("0.1.2.3", false),
+            ("::", false),
+            ("::ffff:127.0.0.1", false),
+            ("::ffff:10.0.0.1", false),
+            ("::ffff:169.254.169.254", false),
+            ("64:ff9b::a00:1", false),
+            ("64:ff9b::808:808", false),
+            ("8.8.8.8", true),
+            ("100.128.0.1", true),
+            ("::ffff:8.8.8.8", true),
+            ("2606:4700:4700::1111", true),
+        ];
+
+        for (ip, public) in cases {
+            assert_eq!(is_public(ip.parse().unwrap()), public, "{ip}");
+        }
+    }
+}
@@ -1,16 +1,22 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    collections::{HashMap, HashSet},
     io::ErrorKind,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
 
 use async_trait::async_trait;
 use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, RwLock};
 
-use crate::cache;
+use crate::{
+    archive::ArchiveFormat, auth, cache, config::Config, metrics::Metrics, rate_limit::RateLimiter,
+    remote, tus::TusUpload,
+};
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -19,9 +25,38 @@ pub struct UploadRecord {
     pub file: PathBuf,
     pub downloads: u8,
     pub max_downloads: u8,
+    pub last_downloaded: Option<DateTime<Utc>>,
+    /// Expires the record once it has gone this long without a download,
+    /// on top of the usual expiry
+    pub idle_expiry: Option<std::time::Duration>,
+    pub format: ArchiveFormat,
+    /// Deflate level the upload asked for, if it picked one
+    pub compression_level: Option<u32>,
+    /// Size of the archive on disk in bytes
+    pub size: u64,
+    /// Combined size of every file that went into the archive
+    pub uncompressed_size: u64,
+    /// Hash of the token handed to the uploader that lets them delete the
+    /// upload, records from before delete tokens can only be removed by admins
+    pub delete_token_hash: Option<String>,
+    /// Hash of the uploaded files, records with the same hash share `file`
+    pub hash: Option<String>,
+    /// Deletes the record, and its file, as soon as it is downloaded once
+    pub burn: bool,
+    /// Address the upload came from, hashed when `Config::hash_uploader_ips` is set
+    pub uploader: Option<String>,
+    /// How long after the upload the record expires, before any extensions
+    pub lifetime: std::time::Duration,
+    /// How far the expiry has been pushed back past `lifetime`
+    pub extended: std::time::Duration,
 }
 
 impl UploadRecord {
+    pub const DEFAULT_MAX_DOWNLOADS: u8 = 5;
+    pub const DEFAULT_LIFETIME: std::time::Duration =
+        std::time::Duration::from_secs(3 * 24 * 60 * 60);
+    pub const MAX_EXTENSION_DAYS: u64 = 365;
+
     pub fn new(file: PathBuf) -> Self {
         Self {
             file,
@@ -30,13 +65,55 @@ impl UploadRecord {
     }
 
     pub fn can_be_downloaded(&self) -> bool {
-        let dur_since_upload = Utc::now().signed_duration_since(self.uploaded);
+        self.can_be_downloaded_at(Utc::now())
+    }
+
+    pub fn can_be_downloaded_at(&self, now: DateTime<Utc>) -> bool {
+        now < self.expires_at() && !self.is_idle(now) && self.downloads < self.max_downloads
+    }
+
+    fn is_idle(&self, now: DateTime<Utc>) -> bool {
+        let Some(idle_expiry) = self
+            .idle_expiry
+            .and_then(|idle| Duration::from_std(idle).ok())
+        else {
+            return false;
+        };
+
+        let last_active = self.last_downloaded.unwrap_or(self.uploaded);
+        now.signed_duration_since(last_active) >= idle_expiry
+    }
 
-        dur_since_upload < Duration::days(3) && self.downloads < self.max_downloads
+    pub fn delete_token_matches(&self, token: &str) -> bool {
+        self.delete_token_hash
+            .as_deref()
+            .is_some_and(|hash| hash == auth::hash_token(token))
+    }
+
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        let lifetime =
+            Duration::from_std(self.lifetime + self.extended).unwrap_or_else(|_| Duration::days(3));
+        self.uploaded + lifetime
+    }
+
+    /// Pushes the expiry back by `days`, up to `MAX_EXTENSION_DAYS` in total
+    pub fn extend(&mut self, days: u64) {
+        let max = std::time::Duration::from_secs(Self::MAX_EXTENSION_DAYS * 24 * 60 * 60);
+        let days = std::time::Duration::from_secs(days.saturating_mul(24 * 60 * 60));
+        self.extended = self.extended.saturating_add(days).min(max);
     }
 
     pub fn downloads_remaining(&self) -> u8 {
-        self.max_downloads - self.downloads
+        self.max_downloads.saturating_sub(self.downloads)
+    }
+
+    /// Validator for the archive, which never changes once it is written
+    pub fn etag(&self) -> String {
+        match &self.hash {
+            Some(hash) => format!("\"{hash}\""),
+            // Older records have no hash, but their archive is just as fixed
+            None => format!("\"{:x}-{:x}\"", self.uploaded.timestamp(), self.size),
+        }
     }
 }
 
@@ -46,52 +123,497 @@ impl Default for UploadRecord {
             uploaded: Utc::now(),
             file: Path::new("").to_owned(),
             downloads: 0,
-            max_downloads: 5,
+            max_downloads: Self::DEFAULT_MAX_DOWNLOADS,
+            last_downloaded: None,
+            idle_expiry: None,
+            format: ArchiveFormat::Zip,
+            compression_level: None,
+            size: 0,
+            uncompressed_size: 0,
+            delete_token_hash: None,
+            hash: None,
+            burn: false,
+            uploader: None,
+            lifetime: Self::DEFAULT_LIFETIME,
+            extended: std::time::Duration::ZERO,
         }
     }
 }
 
+/// Clones out every record, newest upload first, so pages stay stable between requests
+pub fn sorted_records(records: &HashMap<String, UploadRecord>) -> Vec<(String, UploadRecord)> {
+    let mut sorted: Vec<_> = records
+        .iter()
+        .map(|(id, record)| (id.clone(), record.clone()))
+        .collect();
+    sorted.sort_by(|(a_id, a), (b_id, b)| b.uploaded.cmp(&a.uploaded).then_with(|| a_id.cmp(b_id)));
+    sorted
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct RecordStats {
+    pub active_records: usize,
+    /// Expired records still waiting on the next cleaning sweep
+    pub expired_records: usize,
+    /// Combined size of every stored archive, expired or not
+    pub total_bytes: u64,
+}
+
+impl RecordStats {
+    pub fn collect(records: &HashMap<String, UploadRecord>) -> Self {
+        let now = Utc::now();
+
+        let mut counted = HashSet::new();
+        records.values().fold(Self::default(), |mut stats, record| {
+            if record.can_be_downloaded_at(now) {
+                stats.active_records += 1;
+            } else {
+                stats.expired_records += 1;
+            }
+            // Archives shared between identical uploads only take up space once
+            if counted.insert(&record.file) {
+                stats.total_bytes += record.size;
+            }
+            stats
+        })
+    }
+}
+
 #[derive(Clone)]
 pub struct AppState {
+    pub config: Arc<Config>,
     pub records: Arc<Mutex<HashMap<String, UploadRecord>>>,
+    pub upload_limiter: RateLimiter,
+    pub metrics: Arc<Metrics>,
+    /// Most recently fetched cat fact, kept fresh in the background so pages
+    /// never wait on the cat fact api
+    pub cat_fact: Arc<RwLock<Option<String>>>,
+    /// Resumable uploads that are still coming in, by their tus id
+    pub tus_uploads: Arc<Mutex<HashMap<String, TusUpload>>>,
+    /// Bytes set aside for uploads still in progress, see `Reservation`
+    pub reserved_bytes: Arc<AtomicU64>,
+    /// Running total of `RecordStats::total_bytes`, only changed while the
+    /// records are locked so it always matches them
+    stored_bytes: Arc<AtomicU64>,
+    /// Fetches files for uploads that name a url instead of sending the file
+    pub remote: Arc<dyn remote::Source>,
 }
 
 impl AppState {
-    pub fn new(records: HashMap<String, UploadRecord>) -> Self {
+    pub fn new(config: Config, records: HashMap<String, UploadRecord>) -> Self {
         Self {
+            config: Arc::new(config),
+            stored_bytes: Arc::new(AtomicU64::new(RecordStats::collect(&records).total_bytes)),
             records: Arc::new(Mutex::new(records)),
+            upload_limiter: RateLimiter::default(),
+            metrics: Arc::new(Metrics::default()),
+            cat_fact: Arc::new(RwLock::new(None)),
+            tus_uploads: Arc::new(Mutex::new(HashMap::new())),
+            reserved_bytes: Arc::new(AtomicU64::new(0)),
+            remote: Arc::new(remote::Http),
+        }
+    }
+
+    pub fn stored_bytes(&self) -> u64 {
+        self.stored_bytes.load(Ordering::SeqCst)
+    }
+
+    /// Counts a newly stored archive, call with the records still locked
+    pub fn track_stored(&self, bytes: u64) {
+        self.stored_bytes.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    /// Takes archives freed by `remove_record` back off the total, call with
+    /// the records still locked
+    pub fn release_stored(&self, bytes: u64) {
+        let _ = self
+            .stored_bytes
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |stored| {
+                Some(stored.saturating_sub(bytes))
+            });
+    }
+
+    /// Room left under `Config::max_storage` once both the stored archives and
+    /// every reservation are counted, `None` when storage isn't capped
+    pub async fn storage_room(&self) -> Option<u64> {
+        let max = self.config.max_storage?;
+        Some(
+            max.saturating_sub(self.stored_bytes())
+                .saturating_sub(self.reserved_bytes.load(Ordering::SeqCst)),
+        )
+    }
+
+    /// Sets `bytes` aside for an upload, `None` if they don't fit under
+    /// `Config::max_storage`
+    pub async fn reserve(&self, bytes: u64) -> Option<Reservation> {
+        let mut reservation = Reservation {
+            bytes: 0,
+            reserved: self.reserved_bytes.clone(),
+        };
+        self.grow_reservation(&mut reservation, bytes)
+            .await
+            .then_some(reservation)
+    }
+
+    /// Adds `bytes` to an existing reservation, leaving it untouched and
+    /// returning false if they don't fit
+    pub async fn grow_reservation(&self, reservation: &mut Reservation, bytes: u64) -> bool {
+        let Some(max) = self.config.max_storage else {
+            self.reserved_bytes.fetch_add(bytes, Ordering::SeqCst);
+            reservation.bytes += bytes;
+            return true;
+        };
+
+        // Held throughout so nothing gets stored between the count and the check
+        let _records = self.records.lock().await;
+        let stored = self.stored_bytes();
+
+        let grown = self
+            .reserved_bytes
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |reserved| {
+                let total = stored.checked_add(reserved)?.checked_add(bytes)?;
+                (total <= max).then_some(reserved + bytes)
+            })
+            .is_ok();
+        if grown {
+            reservation.bytes += bytes;
+        }
+        grown
+    }
+}
+
+/// Storage set aside for an upload that has no record yet, so concurrent
+/// uploads can't each count on the same free space. Handed back when dropped,
+/// which covers uploads that fail or get abandoned partway through.
+#[derive(Debug)]
+pub struct Reservation {
+    bytes: u64,
+    reserved: Arc<AtomicU64>,
+}
+
+impl Reservation {
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.reserved.fetch_sub(self.bytes, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct CullSummary {
+    pub removed: usize,
+    /// Archives shared with records that are still around aren't counted
+    pub bytes_freed: u64,
+}
+
+/// Removes every record that can no longer be downloaded
+pub async fn cull_expired(state: &AppState) -> CullSummary {
+    let now = Utc::now();
+    let mut records = state.records.lock().await;
+
+    let expired: Vec<String> = records
+        .iter()
+        .filter(|(_, record)| !record.can_be_downloaded_at(now))
+        .map(|(key, _)| key.clone())
+        .collect();
+
+    let mut summary = CullSummary::default();
+    for key in expired {
+        tracing::info!("culling: {:?}", records.get(&key));
+        match records.remove_record(&key).await {
+            Ok(freed) => {
+                state.release_stored(freed);
+                Metrics::inc(&state.metrics.records_culled_total);
+                summary.removed += 1;
+                summary.bytes_freed += freed;
+            }
+            Err(err) => tracing::error!("failed to cull {}: {}", key, err),
+        }
+    }
+
+    if summary.removed > 0 {
+        let snapshot = cache::snapshot(&state.config.data_path(), &records);
+        drop(records);
+        if let Err(err) = snapshot.write().await {
+            tracing::error!("failed to cache records after culling: {}", err);
         }
     }
+
+    summary
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RemovalSummary {
+    pub removed: Vec<String>,
+    pub failed: Vec<RemovalFailure>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RemovalFailure {
+    pub id: String,
+    pub error: String,
+}
+
+/// Removes every record in `ids` it can, carrying on past the ones that fail.
+/// A `dry_run` only reports what would happen, leaving everything in place.
+pub async fn remove_records(state: &AppState, ids: &[String], dry_run: bool) -> RemovalSummary {
+    let mut records = state.records.lock().await;
+    let mut summary = RemovalSummary::default();
+
+    for id in ids {
+        let result = if dry_run {
+            if records.contains_key(id) {
+                Ok(())
+            } else {
+                Err("No UploadRecord Found".to_string())
+            }
+        } else {
+            records
+                .remove_record(id)
+                .await
+                .map(|freed| state.release_stored(freed))
+                .map_err(|err| err.to_string())
+        };
+
+        match result {
+            Ok(()) => summary.removed.push(id.clone()),
+            Err(error) => summary.failed.push(RemovalFailure {
+                id: id.clone(),
+                error,
+            }),
+        }
+    }
+
+    if !dry_run && !summary.removed.is_empty() {
+        let snapshot = cache::snapshot(&state.config.data_path(), &records);
+        drop(records);
+        if let Err(err) = snapshot.write().await {
+            tracing::error!("failed to cache records after removing a batch: {}", err);
+        }
+    }
+
+    summary
 }
 
 #[async_trait]
 pub trait AsyncRemoveRecord {
-    async fn remove_record(&mut self, id: &String) -> Result<(), std::io::Error>;
+    /// Returns the bytes freed on disk, nothing while another record still
+    /// shares the archive
+    async fn remove_record(&mut self, id: &String) -> Result<u64, std::io::Error>;
 }
 
 #[async_trait]
 impl AsyncRemoveRecord for AppState {
-    async fn remove_record(&mut self, id: &String) -> Result<(), std::io::Error> {
+    async fn remove_record(&mut self, id: &String) -> Result<u64, std::io::Error> {
         let mut records = self.records.lock().await;
-        records.remove_record(id).await
+        let freed = records.remove_record(id).await?;
+        self.release_stored(freed);
+
+        let snapshot = cache::snapshot(&self.config.data_path(), &records);
+        drop(records);
+        snapshot.write().await?;
+        Ok(freed)
     }
 }
 
+// Leaves writing the cache and `AppState::release_stored` to the caller, who
+// has the state and may well be removing several records in one go
 #[async_trait]
 impl AsyncRemoveRecord for HashMap<String, UploadRecord> {
-    async fn remove_record(&mut self, id: &String) -> Result<(), std::io::Error> {
-        match self.entry(id.clone()) {
-            Entry::Occupied(entry) => {
-                tokio::fs::remove_file(&entry.get().file).await?;
-                entry.remove_entry();
-                cache::write_to_cache(&self).await?;
-
-                Ok(())
-            }
-            Entry::Vacant(_) => Err(std::io::Error::new(
+    async fn remove_record(&mut self, id: &String) -> Result<u64, std::io::Error> {
+        let Some(record) = self.get(id) else {
+            return Err(std::io::Error::new(
                 ErrorKind::Other,
                 "No UploadRecord Found",
-            )),
+            ));
+        };
+
+        // Identical uploads share an archive, so it stays until nothing refers to it
+        let references = self
+            .values()
+            .filter(|other| other.file == record.file)
+            .count();
+        let mut freed = 0;
+        if references == 1 {
+            tokio::fs::remove_file(&record.file).await?;
+            freed = record.size;
         }
+
+        self.remove(id);
+
+        Ok(freed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util;
+
+    fn capped_state(max_storage: u64, stored: u64) -> AppState {
+        let records = HashMap::from([(
+            "cat".to_owned(),
+            UploadRecord {
+                size: stored,
+                ..UploadRecord::new(PathBuf::from("cat.zip"))
+            },
+        )]);
+        let config = Config {
+            max_storage: Some(max_storage),
+            ..Default::default()
+        };
+        AppState::new(config, records)
+    }
+
+    #[tokio::test]
+    async fn reservations_share_the_ceiling() {
+        let state = capped_state(100, 30);
+
+        let first = state
+            .reserve(50)
+            .await
+            .expect("50 of the 70 free bytes fit");
+        assert_eq!(state.storage_room().await, Some(20));
+        assert!(state.reserve(21).await.is_none());
+
+        let mut second = state.reserve(20).await.expect("the last 20 bytes fit");
+        assert!(!state.grow_reservation(&mut second, 1).await);
+        assert_eq!(second.bytes(), 20);
+
+        drop(first);
+        assert_eq!(state.storage_room().await, Some(50));
+        assert!(state.grow_reservation(&mut second, 50).await);
+        assert_eq!(state.storage_room().await, Some(0));
+
+        drop(second);
+        assert_eq!(state.reserved_bytes.load(Ordering::SeqCst), 0);
+    }
+
+    fn at(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    #[test]
+    fn idle_counts_from_the_last_download() {
+        let mut record = UploadRecord {
+            uploaded: at("2023-07-01T00:00:00Z"),
+            idle_expiry: Some(std::time::Duration::from_secs(2 * 24 * 60 * 60)),
+            ..Default::default()
+        };
+
+        // Never downloaded, so the upload itself is the last activity
+        assert!(!record.is_idle(at("2023-07-02T23:59:59Z")));
+        assert!(record.is_idle(at("2023-07-03T00:00:00Z")));
+
+        record.last_downloaded = Some(at("2023-07-02T00:00:00Z"));
+        assert!(!record.is_idle(at("2023-07-03T12:00:00Z")));
+        assert!(record.is_idle(at("2023-07-04T00:00:00Z")));
+    }
+
+    #[test]
+    fn records_without_idle_expiry_never_idle() {
+        let record = UploadRecord {
+            uploaded: at("2023-07-01T00:00:00Z"),
+            ..Default::default()
+        };
+
+        assert!(!record.is_idle(at("2024-07-01T00:00:00Z")));
+    }
+
+    #[test]
+    fn downloadable_until_expiry() {
+        let record = UploadRecord {
+            uploaded: at("2023-07-01T00:00:00Z"),
+            ..Default::default()
+        };
+        assert_eq!(record.expires_at(), at("2023-07-04T00:00:00Z"));
+
+        assert!(record.can_be_downloaded_at(at("2023-07-03T23:59:59Z")));
+        assert!(!record.can_be_downloaded_at(at("2023-07-04T00:00:00Z")));
+        assert!(!record.can_be_downloaded_at(at("2023-07-04T00:00:01Z")));
+    }
+
+    #[test]
+    fn downloadable_until_out_of_downloads() {
+        let now = at("2023-07-02T00:00:00Z");
+        let mut record = UploadRecord {
+            uploaded: at("2023-07-01T00:00:00Z"),
+            max_downloads: 3,
+            downloads: 2,
+            ..Default::default()
+        };
+        assert!(record.can_be_downloaded_at(now));
+
+        record.downloads = 3;
+        assert!(!record.can_be_downloaded_at(now));
+    }
+
+    #[test]
+    fn remaining_downloads_saturate() {
+        let record = |downloads, max_downloads| UploadRecord {
+            downloads,
+            max_downloads,
+            ..Default::default()
+        };
+
+        assert_eq!(record(0, 1).downloads_remaining(), 1);
+        assert_eq!(record(1, 1).downloads_remaining(), 0);
+        // Counts that raced past the max don't wrap around
+        assert_eq!(record(3, 1).downloads_remaining(), 0);
+        assert_eq!(record(0, u8::MAX).downloads_remaining(), u8::MAX);
+    }
+
+    #[test]
+    fn stats_sum_known_sizes() {
+        let record = |file: &str, size: u64| UploadRecord {
+            size,
+            ..UploadRecord::new(PathBuf::from(file))
+        };
+        let records = HashMap::from([
+            ("cat".to_owned(), record("cat.zip", 100)),
+            // Shares its archive with cat, so it isn't counted twice
+            ("cat-again".to_owned(), record("cat.zip", 100)),
+            ("dog".to_owned(), record("dog.zip", 250)),
+            (
+                "old".to_owned(),
+                UploadRecord {
+                    uploaded: at("2020-01-01T00:00:00Z"),
+                    ..record("old.zip", 25)
+                },
+            ),
+        ]);
+
+        let stats = RecordStats::collect(&records);
+        assert_eq!(stats.active_records, 3);
+        assert_eq!(stats.expired_records, 1);
+        assert_eq!(stats.total_bytes, 375);
+    }
+
+    #[tokio::test]
+    async fn culling_removes_expired_records() {
+        let state = test_util::state().await;
+        let expired = UploadRecord {
+            uploaded: at("2020-01-01T00:00:00Z"),
+            ..Default::default()
+        };
+        let old = test_util::insert_upload(&state, "old", &[("old.txt", "meow")], expired).await;
+        let new =
+            test_util::insert_upload(&state, "new", &[("new.txt", "mrrp")], Default::default())
+                .await;
+
+        let summary = cull_expired(&state).await;
+        assert_eq!(summary.removed, 1);
+        assert_eq!(summary.bytes_freed, old.size);
+        assert_eq!(state.stored_bytes(), new.size);
+
+        let records = state.records.lock().await;
+        assert!(!records.contains_key("old"));
+        assert!(records.contains_key("new"));
+        assert!(!old.file.exists());
     }
 }
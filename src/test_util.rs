@@ -0,0 +1,220 @@
+//! Shared setup for tests that go through the whole app
+
+use axum::{
+    body::{Body, Bytes},
+    extract::connect_info::MockConnectInfo,
+    http::{header, Request, StatusCode},
+    response::Response,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
+
+use tower::ServiceExt;
+
+use crate::{
+    archive::{ArchiveFormat, ArchiveWriter},
+    config::Config,
+    state::{AppState, UploadRecord},
+    util,
+};
+
+pub const ADMIN_TOKEN: &str = "meow-meow-admin";
+
+/// A fresh directory under the system temp dir
+pub fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("nyazoom-test-{}", util::get_random_name(12)));
+    std::fs::create_dir_all(&dir).expect("temp dir should be writable");
+    dir
+}
+
+/// Defaults, but with its own data dir, a known admin token, and no rate limit
+pub fn config() -> Config {
+    Config {
+        data_dir: temp_dir(),
+        admin_token: Some(ADMIN_TOKEN.to_owned()),
+        upload_limit: 0,
+        ..Default::default()
+    }
+}
+
+pub async fn state() -> AppState {
+    state_with(config()).await
+}
+
+pub async fn state_with(config: Config) -> AppState {
+    util::make_dir(config.serve_dir())
+        .await
+        .expect("serve dir should be creatable");
+    AppState::new(config, HashMap::new())
+}
+
+/// Sends `request` through the full router, as if from 127.0.0.1
+pub async fn send(state: &AppState, mut request: Request<Body>) -> Response {
+    // Any real client sends one, and links are built from it
+    request
+        .headers_mut()
+        .entry(header::HOST)
+        .or_insert(header::HeaderValue::from_static("nyazoom.test"));
+
+    crate::app(state.clone())
+        .layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))))
+        .oneshot(request)
+        .await
+        .expect("the router never fails")
+}
+
+pub fn get(uri: &str) -> Request<Body> {
+    Request::get(uri).body(Body::empty()).unwrap()
+}
+
+/// `request`, but carrying the admin token
+pub fn as_admin(mut request: Request<Body>) -> Request<Body> {
+    request.headers_mut().insert(
+        header::AUTHORIZATION,
+        format!("Bearer {ADMIN_TOKEN}").parse().unwrap(),
+    );
+    request
+}
+
+pub async fn body_bytes(response: Response) -> Bytes {
+    hyper::body::to_bytes(response.into_body())
+        .await
+        .expect("bodies are read in full")
+}
+
+pub async fn body_text(response: Response) -> String {
+    String::from_utf8(body_bytes(response).await.to_vec()).expect("body should be utf-8")
+}
+
+pub async fn body_json(response: Response) -> serde_json::Value {
+    serde_json::from_slice(&body_bytes(response).await).expect("body should be json")
+}
+
+/// Stores a zip of `files` under `id`, described by `record` otherwise
+pub async fn insert_upload(
+    state: &AppState,
+    id: &str,
+    files: &[(&str, &str)],
+    record: UploadRecord,
+) -> UploadRecord {
+    let path = state.config.serve_dir().join(format!("{id}.zip"));
+
+    let mut writer = ArchiveWriter::create(ArchiveFormat::Zip, None, &path)
+        .await
+        .unwrap();
+    let mut uncompressed_size = 0;
+    for (name, contents) in files {
+        uncompressed_size += writer
+            .write_entry(
+                name,
+                async_zip::Compression::Deflate,
+                &mut contents.as_bytes(),
+            )
+            .await
+            .unwrap();
+    }
+    let size = writer.close().await.unwrap();
+
+    let record = UploadRecord {
+        file: path,
+        format: ArchiveFormat::Zip,
+        size,
+        uncompressed_size,
+        ..record
+    };
+    let mut records = state.records.lock().await;
+    records.insert(id.to_owned(), record.clone());
+    state.track_stored(size);
+    record
+}
+
+/// Uploads `form` through the json api, returning the new id and its record
+pub async fn upload(state: &AppState, form: Form) -> (String, UploadRecord) {
+    let response = send(state, form.post("/api/upload")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let id = body_json(response).await["id"]
+        .as_str()
+        .expect("uploads respond with their id")
+        .to_owned();
+    let record = state.records.lock().await[&id].clone();
+    (id, record)
+}
+
+/// `POST /tus` for a `length` byte upload of `file_name`
+pub fn tus_create(length: u64, file_name: &str) -> Request<Body> {
+    Request::post("/tus")
+        .header("tus-resumable", "1.0.0")
+        .header("upload-length", length)
+        .header(
+            "upload-metadata",
+            format!("filename {}", STANDARD.encode(file_name)),
+        )
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Builds a multipart/form-data body by hand
+pub struct Form {
+    boundary: String,
+    body: Vec<u8>,
+}
+
+impl Form {
+    pub fn new() -> Self {
+        Self {
+            boundary: format!("nyazoom-{}", util::get_random_name(16)),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn text(mut self, name: &str, value: &str) -> Self {
+        self.part(&format!("name=\"{name}\""), value.as_bytes());
+        self
+    }
+
+    pub fn file(mut self, file_name: &str, contents: &[u8]) -> Self {
+        self.part(
+            &format!("name=\"file\"; filename=\"{file_name}\""),
+            contents,
+        );
+        self
+    }
+
+    /// The finished form, posted to `uri`
+    pub fn post(mut self, uri: &str) -> Request<Body> {
+        self.body
+            .extend_from_slice(format!("--{}--\r\n", self.boundary).as_bytes());
+        self.request(uri)
+    }
+
+    /// The form with its last part cut off partway, as from a dropped connection
+    pub fn post_truncated(mut self, uri: &str) -> Request<Body> {
+        self.body.truncate(self.body.len().saturating_sub(10));
+        self.request(uri)
+    }
+
+    fn part(&mut self, disposition: &str, contents: &[u8]) {
+        self.body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; {disposition}\r\n\r\n",
+                self.boundary
+            )
+            .as_bytes(),
+        );
+        self.body.extend_from_slice(contents);
+        self.body.extend_from_slice(b"\r\n");
+    }
+
+    fn request(self, uri: &str) -> Request<Body> {
+        Request::post(uri)
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", self.boundary),
+            )
+            .body(Body::from(self.body))
+            .unwrap()
+    }
+}
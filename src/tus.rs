@@ -0,0 +1,620 @@
+use axum::{
+    extract::{BodyStream, ConnectInfo, Host, Path, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use chrono::{DateTime, Utc};
+
+use futures::StreamExt;
+
+use std::{
+    collections::HashSet,
+    io::SeekFrom,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use tokio::{
+    io::{AsyncSeekExt, AsyncWriteExt},
+    sync::Mutex,
+};
+
+use crate::{
+    nyazoom_headers,
+    state::{AppState, Reservation},
+    upload::{self, UploadOptions, UploadResponse},
+    util,
+};
+
+pub static VERSION: &str = "1.0.0";
+
+static TUS_RESUMABLE: &str = "tus-resumable";
+static TUS_VERSION: &str = "tus-version";
+static UPLOAD_LENGTH: &str = "upload-length";
+static UPLOAD_OFFSET: &str = "upload-offset";
+static UPLOAD_METADATA: &str = "upload-metadata";
+
+// Unfinished uploads only live in memory, so there is no point holding on to
+// ones the client seems to have given up on
+const STALE_AFTER_HOURS: i64 = 24;
+
+// What uploads still coming in are called in the serve dir, the chunks of tus
+// uploads and the archives and tar spools of multipart ones
+const IN_PROGRESS_EXTENSIONS: [&str; 3] = ["tus", "part", "spool"];
+
+/// A resumable upload still waiting on some of its bytes
+pub struct TusUpload {
+    /// Where the chunks pile up until there are `length` bytes of them
+    path: PathBuf,
+    length: u64,
+    offset: u64,
+    file_name: String,
+    options: UploadOptions,
+    client_ip: IpAddr,
+    last_active: DateTime<Utc>,
+    /// Held while a chunk is being written, so two can't land at the same offset
+    writing: Arc<Mutex<()>>,
+    /// The chunks take up room before there is a record to count them, so the
+    /// full length is set aside up front
+    reservation: Reservation,
+}
+
+/// `POST /tus`, sets aside an upload of `Upload-Length` bytes. Its
+/// `Upload-Metadata` can name the file and set any of the usual upload options.
+pub async fn create(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    with_version(create_upload(&state, addr, &headers).await)
+}
+
+/// `HEAD /tus/:id`, tells a client where to resume from
+pub async fn offset(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    with_version(upload_offset(&state, &id, &headers).await)
+}
+
+/// `PATCH /tus/:id`, appends a chunk at `Upload-Offset`. The chunk that
+/// finishes the upload archives it, the new link comes back in the headers.
+pub async fn append(
+    State(state): State<AppState>,
+    Host(host): Host,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> Response {
+    with_version(append_chunk(&state, &host, &id, &headers, body).await)
+}
+
+async fn create_upload(
+    state: &AppState,
+    addr: SocketAddr,
+    headers: &HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    check_version(headers)?;
+
+    // Deferring the length is an extension, which isn't on offer
+    let length = header_u64(headers, UPLOAD_LENGTH)?.ok_or_else(|| {
+        (
+            StatusCode::BAD_REQUEST,
+            "Upload-Length is required".to_string(),
+        )
+    })?;
+
+    if let Some(max) = state.config.max_entry_size.filter(|max| length > *max) {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Files are limited to {} bytes each", max),
+        ));
+    }
+
+    let max = state.config.upload_size_limit();
+    if length > max {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Uploads are limited to {} bytes in total", max),
+        ));
+    }
+
+    let mut file_name = None;
+    let mut options = UploadOptions::new(&state.config);
+    for (key, value) in parse_metadata(headers)? {
+        match key.as_str() {
+            "filename" | "name" => file_name = Some(value),
+            key if UploadOptions::is_option(key) => options.set(key, &value)?,
+            _ => {}
+        }
+    }
+
+    // Same early check as the multipart upload, rather than after every byte is in
+    if let Some(slug) = options.slug() {
+        if state.records.lock().await.contains_key(slug) {
+            return Err(upload::slug_taken(slug));
+        }
+    }
+
+    let reservation = state
+        .reserve(length)
+        .await
+        .ok_or_else(upload::out_of_storage)?;
+
+    let id = util::get_random_name(16);
+    let path = state.config.serve_dir().join(format!("{id}.tus"));
+    tokio::fs::File::create(&path)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+    state.tus_uploads.lock().await.insert(
+        id.clone(),
+        TusUpload {
+            path,
+            length,
+            offset: 0,
+            file_name: file_name.unwrap_or_else(|| id.clone()),
+            options,
+            client_ip: nyazoom_headers::resolve_client_ip(
+                headers,
+                addr.ip(),
+                &state.config.trusted_proxies,
+            ),
+            last_active: Utc::now(),
+            writing: Arc::default(),
+            reservation,
+        },
+    );
+
+    Ok((
+        StatusCode::CREATED,
+        [
+            ("location", format!("/tus/{id}")),
+            (UPLOAD_OFFSET, "0".to_string()),
+        ],
+    )
+        .into_response())
+}
+
+async fn upload_offset(
+    state: &AppState,
+    id: &str,
+    headers: &HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    check_version(headers)?;
+
+    let uploads = state.tus_uploads.lock().await;
+    let upload = uploads.get(id).ok_or_else(not_found)?;
+
+    Ok((
+        StatusCode::OK,
+        [
+            (UPLOAD_OFFSET, upload.offset.to_string()),
+            (UPLOAD_LENGTH, upload.length.to_string()),
+            ("cache-control", "no-store".to_string()),
+        ],
+    )
+        .into_response())
+}
+
+async fn append_chunk(
+    state: &AppState,
+    host: &str,
+    id: &str,
+    headers: &HeaderMap,
+    body: BodyStream,
+) -> Result<Response, (StatusCode, String)> {
+    check_version(headers)?;
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    if content_type != Some("application/offset+octet-stream") {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Chunks have to be sent as application/offset+octet-stream".to_string(),
+        ));
+    }
+
+    let offset = header_u64(headers, UPLOAD_OFFSET)?.ok_or_else(|| {
+        (
+            StatusCode::BAD_REQUEST,
+            "Upload-Offset is required".to_string(),
+        )
+    })?;
+
+    // The guard lets go even if the request is dropped partway through
+    let (path, remaining, _writing) = {
+        let uploads = state.tus_uploads.lock().await;
+        let upload = uploads.get(id).ok_or_else(not_found)?;
+
+        let writing = upload.writing.clone().try_lock_owned().map_err(|_| {
+            (
+                StatusCode::LOCKED,
+                "Another chunk is still being written".to_string(),
+            )
+        })?;
+        if upload.offset != offset {
+            return Err((
+                StatusCode::CONFLICT,
+                format!("The upload is at offset {}", upload.offset),
+            ));
+        }
+
+        (upload.path.clone(), upload.length - upload.offset, writing)
+    };
+
+    let (written, result) = write_chunk(&path, offset, remaining, body).await;
+
+    let mut uploads = state.tus_uploads.lock().await;
+    let upload = uploads
+        .get_mut(id)
+        .expect("uploads being written to are never culled");
+    upload.offset += written;
+    upload.last_active = Utc::now();
+    result?;
+
+    if upload.offset < upload.length {
+        return Ok((
+            StatusCode::NO_CONTENT,
+            [(UPLOAD_OFFSET, upload.offset.to_string())],
+        )
+            .into_response());
+    }
+
+    let upload = uploads.remove(id).expect("the upload was just updated");
+    drop(uploads);
+
+    let stored = upload::archive_file(
+        state,
+        upload.client_ip,
+        upload.options,
+        &upload.file_name,
+        &upload.path,
+        Some(upload.reservation),
+    )
+    .await;
+    tokio::fs::remove_file(&upload.path).await.ok();
+    let stored = UploadResponse::new(stored?, &state.config.base_url(host));
+
+    // A 204 can't carry a body, so the link is handed over in headers instead
+    Ok((
+        StatusCode::NO_CONTENT,
+        [
+            (UPLOAD_OFFSET, upload.length.to_string()),
+            ("x-upload-id", stored.id),
+            ("x-upload-url", stored.url),
+            ("x-delete-token", stored.delete_token),
+        ],
+    )
+        .into_response())
+}
+
+// Writes as much of `body` as fits in `remaining`, returning how many bytes
+// made it to disk even when the body breaks off partway, as that's where the
+// client resumes from
+async fn write_chunk(
+    path: &std::path::Path,
+    offset: u64,
+    remaining: u64,
+    mut body: BodyStream,
+) -> (u64, Result<(), (StatusCode, String)>) {
+    let internal = |err: std::io::Error| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string());
+
+    let mut file = match tokio::fs::OpenOptions::new().write(true).open(path).await {
+        Ok(file) => file,
+        Err(err) => return (0, Err(internal(err))),
+    };
+
+    // Drops anything a failed chunk left past the offset
+    let prepared = async {
+        file.set_len(offset).await?;
+        file.seek(SeekFrom::Start(offset)).await
+    };
+    if let Err(err) = prepared.await {
+        return (0, Err(internal(err)));
+    }
+
+    let mut written = 0;
+    let mut result = Ok(());
+    while let Some(chunk) = body.next().await {
+        // A dropped connection keeps what arrived before it, that's the point
+        let Ok(chunk) = chunk else {
+            break;
+        };
+
+        let len = chunk.len() as u64;
+        if written + len > remaining {
+            result = Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "The chunk runs past Upload-Length".to_string(),
+            ));
+            break;
+        }
+
+        if let Err(err) = file.write_all(&chunk).await {
+            result = Err(internal(err));
+            break;
+        }
+        written += len;
+    }
+
+    if let Err(err) = file.flush().await {
+        return (0, Err(internal(err)));
+    }
+
+    (written, result)
+}
+
+/// Drops uploads that haven't seen a chunk in a while, along with their files
+pub async fn cull_stale(state: &AppState) {
+    let cutoff = Utc::now() - chrono::Duration::hours(STALE_AFTER_HOURS);
+    let mut uploads = state.tus_uploads.lock().await;
+
+    let stale: Vec<String> = uploads
+        .iter()
+        .filter(|(_, upload)| upload.last_active < cutoff && upload.writing.try_lock().is_ok())
+        .map(|(id, _)| id.clone())
+        .collect();
+
+    for id in stale {
+        if let Some(upload) = uploads.remove(&id) {
+            tracing::info!("dropping stale tus upload {}", id);
+            tokio::fs::remove_file(&upload.path).await.ok();
+        }
+    }
+}
+
+/// Deletes files from uploads that never finished and that nothing is tracking
+/// anymore, like ones cut off by a restart, once they've sat untouched as long
+/// as a stale upload
+pub async fn cull_orphaned_files(state: &AppState) {
+    let cutoff = SystemTime::now() - Duration::from_secs(STALE_AFTER_HOURS as u64 * 60 * 60);
+    let live: HashSet<PathBuf> = state
+        .tus_uploads
+        .lock()
+        .await
+        .values()
+        .map(|upload| upload.path.clone())
+        .collect();
+
+    let mut entries = match tokio::fs::read_dir(state.config.serve_dir()).await {
+        Ok(entries) => entries,
+        Err(err) => {
+            tracing::error!("failed to look for orphaned uploads: {}", err);
+            return;
+        }
+    };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        let in_progress = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| IN_PROGRESS_EXTENSIONS.contains(&extension));
+        if !in_progress || live.contains(&path) {
+            continue;
+        }
+
+        let untouched = entry
+            .metadata()
+            .await
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| modified < cutoff);
+        if untouched {
+            tracing::info!("removing orphaned upload file {:?}", path);
+            tokio::fs::remove_file(&path).await.ok();
+        }
+    }
+}
+
+// Every response has to carry the protocol version, errors included
+fn with_version(result: Result<Response, (StatusCode, String)>) -> Response {
+    let mut response = result.into_response();
+    response
+        .headers_mut()
+        .insert(TUS_RESUMABLE, HeaderValue::from_static(VERSION));
+    if response.status() == StatusCode::PRECONDITION_FAILED {
+        response
+            .headers_mut()
+            .insert(TUS_VERSION, HeaderValue::from_static(VERSION));
+    }
+    response
+}
+
+fn check_version(headers: &HeaderMap) -> Result<(), (StatusCode, String)> {
+    match headers
+        .get(TUS_RESUMABLE)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(version) if version == VERSION => Ok(()),
+        _ => Err((
+            StatusCode::PRECONDITION_FAILED,
+            format!("Only tus {VERSION} is supported"),
+        )),
+    }
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Result<Option<u64>, (StatusCode, String)> {
+    headers
+        .get(name)
+        .map(|value| {
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+                .ok_or_else(|| {
+                    (
+                        StatusCode::BAD_REQUEST,
+                        format!("{name} must be a non-negative number"),
+                    )
+                })
+        })
+        .transpose()
+}
+
+// Comma separated `key value` pairs, with base64 values that may be left out
+fn parse_metadata(headers: &HeaderMap) -> Result<Vec<(String, String)>, (StatusCode, String)> {
+    let Some(metadata) = headers.get(UPLOAD_METADATA) else {
+        return Ok(Vec::new());
+    };
+
+    let invalid = || {
+        (
+            StatusCode::BAD_REQUEST,
+            "Upload-Metadata is malformed".to_string(),
+        )
+    };
+
+    metadata
+        .to_str()
+        .map_err(|_| invalid())?
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once(' ').unwrap_or((pair, ""));
+            let value = STANDARD
+                .decode(value.trim())
+                .ok()
+                .and_then(|value| String::from_utf8(value).ok())
+                .ok_or_else(invalid)?;
+            Ok((key.to_owned(), value))
+        })
+        .collect()
+}
+
+fn not_found() -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, "No such upload".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::{body::Body, http::Request};
+
+    use std::sync::atomic::Ordering;
+
+    use crate::{
+        archive,
+        config::{self, Config},
+        test_util,
+    };
+
+    fn patch(location: &str, offset: u64, chunk: &'static str) -> Request<Body> {
+        Request::patch(location)
+            .header(TUS_RESUMABLE, VERSION)
+            .header(header::CONTENT_TYPE, "application/offset+octet-stream")
+            .header(UPLOAD_OFFSET, offset)
+            .body(Body::from(chunk))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn uploads_finish_across_chunks() {
+        let state = test_util::state().await;
+
+        let response = test_util::send(&state, test_util::tus_create(8, "cat.txt")).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let location = response.headers()["location"].to_str().unwrap().to_owned();
+
+        let response = test_util::send(&state, patch(&location, 0, "meow")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()[UPLOAD_OFFSET], "4");
+
+        let head = Request::head(&location)
+            .header(TUS_RESUMABLE, VERSION)
+            .body(Body::empty())
+            .unwrap();
+        let response = test_util::send(&state, head).await;
+        assert_eq!(response.headers()[UPLOAD_OFFSET], "4");
+        assert_eq!(response.headers()[UPLOAD_LENGTH], "8");
+
+        // Resending from an offset that's already been passed is refused
+        let response = test_util::send(&state, patch(&location, 0, "meow")).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let response = test_util::send(&state, patch(&location, 4, "purr")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let id = response.headers()["x-upload-id"]
+            .to_str()
+            .unwrap()
+            .to_owned();
+
+        let record = state.records.lock().await[&id].clone();
+        assert_eq!(record.uncompressed_size, 8);
+        let entries = archive::list_entries(record.format, &record.file)
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "cat.txt");
+
+        assert!(state.tus_uploads.lock().await.is_empty());
+        assert_eq!(state.reserved_bytes.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn lengths_past_the_limit_are_refused() {
+        let state = test_util::state().await;
+        let response = test_util::send(
+            &state,
+            test_util::tus_create(config::UPLOAD_SIZE_CEILING + 1, "huge.bin"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let state = test_util::state_with(Config {
+            max_upload_size: Some(10),
+            ..test_util::config()
+        })
+        .await;
+        let response = test_util::send(&state, test_util::tus_create(11, "cat.txt")).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let response = test_util::send(&state, test_util::tus_create(10, "cat.txt")).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(state.records.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn orphaned_files_are_culled() {
+        let state = test_util::state().await;
+        let serve_dir = state.config.serve_dir();
+
+        let long_ago = SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60);
+        let file = |name: &str, modified: SystemTime| {
+            let path = serve_dir.join(name);
+            std::fs::File::create(&path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+            path
+        };
+        let orphans = [
+            file("old.tus", long_ago),
+            file("old.zip.part", long_ago),
+            file("old.tar.gz.spool", long_ago),
+        ];
+        let kept = [
+            // Still being written to
+            file("new.zip.part", SystemTime::now()),
+            // Finished archives are the records' business
+            file("done.zip", long_ago),
+        ];
+
+        cull_orphaned_files(&state).await;
+
+        for path in orphans {
+            assert!(!path.exists(), "{path:?}");
+        }
+        for path in kept {
+            assert!(path.exists(), "{path:?}");
+        }
+    }
+}
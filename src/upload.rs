@@ -0,0 +1,1078 @@
+use async_zip::Compression;
+
+use axum::{extract::Multipart, http::StatusCode};
+
+use chrono::{DateTime, Utc};
+
+use futures::TryStreamExt;
+
+use serde::Serialize;
+
+use sha2::{Digest, Sha256};
+
+use std::{
+    collections::HashSet,
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+
+use tokio_util::io::StreamReader;
+
+use crate::{
+    archive::{ArchiveFormat, ArchiveWriter},
+    auth, cache,
+    config::Config,
+    metrics::Metrics,
+    state::{AppState, Reservation, UploadRecord},
+    util,
+};
+
+pub struct StoredUpload {
+    pub id: String,
+    pub record: UploadRecord,
+    /// Lets the uploader delete the upload, only ever handed to them as the
+    /// record just keeps a hash of it
+    pub delete_token: String,
+}
+
+/// Archives every file in the multipart `body` and records it under a new id
+pub async fn archive_upload(
+    state: &AppState,
+    client_ip: IpAddr,
+    body: Multipart,
+) -> Result<StoredUpload, (StatusCode, String)> {
+    tracing::debug!("{:?}", *state.records.lock().await);
+
+    let mut archive = PendingArchive::new(state, None).await?;
+    let options = match write_archive(state, body, &mut archive).await {
+        Ok(options) => options,
+        Err(err) => {
+            archive.discard().await;
+            return Err(err);
+        }
+    };
+
+    store_archive(state, client_ip, options, archive).await
+}
+
+/// Archives a single file that was already received in full, like a finished
+/// resumable upload, and records it under a new id. Storage the file already
+/// had set aside is carried over to the archive.
+pub async fn archive_file(
+    state: &AppState,
+    client_ip: IpAddr,
+    options: UploadOptions,
+    file_name: &str,
+    path: &Path,
+    reservation: Option<Reservation>,
+) -> Result<StoredUpload, (StatusCode, String)> {
+    let file = tokio::fs::File::open(path)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+    let mut archive = PendingArchive::new(state, reservation).await?;
+    let file_name = util::safe_entry_name(file_name, state.config.max_name_length);
+    if let Err(err) = archive
+        .add_entry(state, &options, file_name, Box::pin(file))
+        .await
+    {
+        archive.discard().await;
+        return Err(err);
+    }
+
+    store_archive(state, client_ip, options, archive).await
+}
+
+// Moves a finished archive into place and records it, unless the id is taken
+// by the time the upload is done
+async fn store_archive(
+    state: &AppState,
+    client_ip: IpAddr,
+    options: UploadOptions,
+    mut archive: PendingArchive,
+) -> Result<StoredUpload, (StatusCode, String)> {
+    let written = match archive.finish(&state.config, &options).await {
+        Ok(written) => written,
+        Err(err) => {
+            archive.discard().await;
+            return Err(err);
+        }
+    };
+    let part_path = written.part_path;
+
+    let mut records = state.records.lock().await;
+
+    let id = options.slug.unwrap_or(archive.name);
+    if records.contains_key(&id) {
+        tokio::fs::remove_file(&part_path).await.ok();
+        return Err(slug_taken(&id));
+    }
+
+    // Identical uploads share a single archive, it is only deleted once the
+    // last record pointing at it goes
+    let shared = records
+        .values()
+        .find(|record| record.hash.as_deref() == Some(written.hash.as_str()))
+        .map(|record| (record.file.clone(), record.size));
+
+    let (archive_path, size) = match shared {
+        Some((file, size)) => {
+            tracing::info!("reusing {:?} for an identical upload", file);
+            tokio::fs::remove_file(&part_path).await.ok();
+            (file, size)
+        }
+        None => {
+            // Only complete archives ever sit at their final path
+            let archive_path = part_path.with_extension("");
+            if let Err(err) = tokio::fs::rename(&part_path, &archive_path).await {
+                tokio::fs::remove_file(&part_path).await.ok();
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+            }
+            state.track_stored(written.size);
+            (archive_path, written.size)
+        }
+    };
+
+    let delete_token = auth::generate_token();
+    let record = UploadRecord {
+        idle_expiry: options.idle_expiry,
+        format: options.format,
+        compression_level: options.compression_level,
+        burn: options.burn,
+        max_downloads: options.max_downloads,
+        lifetime: options.lifetime,
+        uploader: Some(state.config.uploader_id(client_ip)),
+        size,
+        uncompressed_size: written.uncompressed_size,
+        hash: Some(written.hash),
+        delete_token_hash: Some(auth::hash_token(&delete_token)),
+        ..UploadRecord::new(archive_path)
+    };
+    records.insert(id.clone(), record.clone());
+    Metrics::inc(&state.metrics.uploads_total);
+
+    let snapshot = cache::snapshot(&state.config.data_path(), &records);
+    drop(records);
+    snapshot
+        .write()
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+    Ok(StoredUpload {
+        id,
+        record,
+        delete_token,
+    })
+}
+
+// Adds every file in the form to `archive`, picking up the options sent along
+// the way
+async fn write_archive(
+    state: &AppState,
+    mut body: Multipart,
+    archive: &mut PendingArchive,
+) -> Result<UploadOptions, (StatusCode, String)> {
+    let config = &state.config;
+    let mut options = UploadOptions::new(config);
+
+    while let Some(field) = body
+        .next_field()
+        .await
+        .map_err(|err| (err.status(), err.body_text()))?
+    {
+        let file_name = field
+            .file_name()
+            .map(|name| util::safe_entry_name(name, config.max_name_length));
+        let (file_name, source): (String, EntrySource) = match file_name {
+            Some(file_name) => {
+                let body = field.map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+                (file_name, Box::pin(StreamReader::new(body)))
+            }
+            // Fetched server side and archived just like an uploaded file
+            None if field.name() == Some("url") => {
+                let url = field
+                    .text()
+                    .await
+                    .map_err(|err| (err.status(), err.body_text()))?;
+                if url.trim().is_empty() {
+                    continue;
+                }
+
+                let (file_name, body) = state.remote.fetch(&url).await?;
+                (
+                    util::safe_entry_name(&file_name, config.max_name_length),
+                    body,
+                )
+            }
+            None => {
+                // Options only apply to the files that come after them in the form
+                if let Some(name) = field.name().filter(|name| UploadOptions::is_option(name)) {
+                    let name = name.to_owned();
+                    let value = field
+                        .text()
+                        .await
+                        .map_err(|err| (err.status(), err.body_text()))?;
+
+                    if matches!(name.as_str(), "format" | "compression_level") && archive.started()
+                    {
+                        return Err((
+                            StatusCode::BAD_REQUEST,
+                            format!("The {name} has to be chosen before any files"),
+                        ));
+                    }
+
+                    options.set(&name, &value)?;
+
+                    // Worth catching before the files are sent, it is checked again
+                    // once the upload is done since someone could take it meanwhile
+                    if let Some(slug) = options.slug.as_ref().filter(|_| name == "slug") {
+                        if state.records.lock().await.contains_key(slug) {
+                            return Err(slug_taken(slug));
+                        }
+                    }
+                }
+                continue;
+            }
+        };
+
+        archive
+            .add_entry(state, &options, file_name, source)
+            .await?;
+    }
+
+    Ok(options)
+}
+
+// Where an entry's contents come from, either the multipart field itself or a
+// remote download
+type EntrySource<'a> = Pin<Box<dyn AsyncRead + Send + 'a>>;
+
+// The archive is only created once the first file arrives, as the format can
+// still be picked up until then. It is written to `part_path` and only moved
+// into place once the upload is done.
+struct PendingArchive {
+    name: String,
+    writer: Option<ArchiveWriter>,
+    part_path: Option<PathBuf>,
+    used_names: HashSet<String>,
+    uncompressed_size: u64,
+    // Covers the names and contents of every entry rather than the archive
+    // itself, which carries timestamps that differ between identical uploads
+    hasher: Sha256,
+    // Grows with every file so the storage ceiling holds across uploads running
+    // side by side, rather than each counting on the same free space
+    reservation: Reservation,
+}
+
+impl PendingArchive {
+    async fn new(
+        state: &AppState,
+        reservation: Option<Reservation>,
+    ) -> Result<Self, (StatusCode, String)> {
+        let reservation = match reservation {
+            Some(reservation) => reservation,
+            None => state.reserve(0).await.ok_or_else(out_of_storage)?,
+        };
+        if reservation.bytes() == 0 && state.storage_room().await == Some(0) {
+            return Err(out_of_storage());
+        }
+
+        Ok(Self {
+            name: util::get_random_name(10),
+            writer: None,
+            part_path: None,
+            used_names: HashSet::new(),
+            uncompressed_size: 0,
+            hasher: Sha256::new(),
+            reservation,
+        })
+    }
+
+    fn started(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    async fn add_entry(
+        &mut self,
+        state: &AppState,
+        options: &UploadOptions,
+        file_name: String,
+        source: EntrySource<'_>,
+    ) -> Result<(), (StatusCode, String)> {
+        let config = &*state.config;
+        let file_name = util::unique_name(file_name, &mut self.used_names, config.max_name_length);
+
+        if self.used_names.len() > config.max_entries {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Uploads are limited to {} files", config.max_entries),
+            ));
+        }
+
+        if self.writer.is_none() {
+            self.writer = Some(self.create(config, options).await?);
+        }
+        let writer = self
+            .writer
+            .as_mut()
+            .expect("the archive was created just above");
+
+        tracing::debug!("Downloading to Archive: {file_name:?}");
+
+        let upload_room = config
+            .upload_size_limit()
+            .saturating_sub(self.uncompressed_size);
+        // Whatever is free, plus what this upload set aside and hasn't used yet
+        let unused = self
+            .reservation
+            .bytes()
+            .saturating_sub(self.uncompressed_size);
+        let storage_room = state
+            .storage_room()
+            .await
+            .map(|room| room.saturating_add(unused));
+
+        // Reading a single byte past the cap is enough to know it was exceeded
+        let entry_limit = [config.max_entry_size, storage_room, Some(upload_room)]
+            .into_iter()
+            .flatten()
+            .map(|limit| limit.saturating_add(1))
+            .min()
+            .unwrap_or(u64::MAX);
+        let mut body_reader = source.take(entry_limit);
+
+        let compression = entry_compression(&file_name, options.compression);
+        self.hasher.update(file_name.as_bytes());
+        self.hasher.update(b"\0");
+        self.hasher.update(format!("{compression:?}"));
+
+        let copied = writer
+            .write_entry(
+                &file_name,
+                compression,
+                &mut HashingReader::new(&mut body_reader, &mut self.hasher),
+            )
+            .await
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        self.hasher.update(copied.to_le_bytes());
+
+        if let Some(max) = config.max_entry_size.filter(|max| copied > *max) {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Files are limited to {} bytes each", max),
+            ));
+        }
+
+        let total = self.uncompressed_size.saturating_add(copied);
+        let max = config.upload_size_limit();
+        if total > max {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Uploads are limited to {} bytes in total", max),
+            ));
+        }
+
+        let unreserved = total.saturating_sub(self.reservation.bytes());
+        if unreserved > 0
+            && !state
+                .grow_reservation(&mut self.reservation, unreserved)
+                .await
+        {
+            return Err(out_of_storage());
+        }
+
+        self.uncompressed_size = total;
+
+        Ok(())
+    }
+
+    async fn finish(
+        &mut self,
+        config: &Config,
+        options: &UploadOptions,
+    ) -> Result<WrittenArchive, (StatusCode, String)> {
+        // An upload without any files still makes for a valid, empty, archive
+        let writer = match self.writer.take() {
+            Some(writer) => writer,
+            None => self.create(config, options).await?,
+        };
+
+        let size = writer
+            .close()
+            .await
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+        let mut hasher = std::mem::take(&mut self.hasher);
+        hasher.update(options.format.extension());
+        hasher.update(format!("{:?}", options.compression_level));
+
+        Ok(WrittenArchive {
+            size,
+            uncompressed_size: self.uncompressed_size,
+            hash: format!("{:x}", hasher.finalize()),
+            part_path: self
+                .part_path
+                .clone()
+                .expect("finished archives always exist on disk"),
+        })
+    }
+
+    /// Never leave a partial archive lying around without a record pointing to it
+    async fn discard(self) {
+        if let Some(part_path) = self.part_path {
+            tokio::fs::remove_file(part_path).await.ok();
+        }
+    }
+
+    async fn create(
+        &mut self,
+        config: &Config,
+        options: &UploadOptions,
+    ) -> Result<ArchiveWriter, (StatusCode, String)> {
+        let path =
+            config
+                .serve_dir()
+                .join(format!("{}.{}.part", self.name, options.format.extension()));
+
+        tracing::debug!("Archiving to: {:?}", &path);
+
+        let writer = ArchiveWriter::create(options.format, options.compression_level, &path).await;
+        self.part_path = Some(path);
+
+        writer.map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+    }
+}
+
+struct WrittenArchive {
+    size: u64,
+    uncompressed_size: u64,
+    /// Identifies uploads with the same files, see `PendingArchive`
+    hash: String,
+    part_path: PathBuf,
+}
+
+/// Feeds everything read through it into `hasher`
+struct HashingReader<'a, R> {
+    inner: R,
+    hasher: &'a mut Sha256,
+}
+
+impl<'a, R> HashingReader<'a, R> {
+    fn new(inner: R, hasher: &'a mut Sha256) -> Self {
+        Self { inner, hasher }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            self.hasher.update(&buf.filled()[filled..]);
+        }
+        poll
+    }
+}
+
+/// Settings an uploader can pick with text fields sent ahead of their files, or
+/// with the metadata of a tus upload
+pub struct UploadOptions {
+    format: ArchiveFormat,
+    compression: Compression,
+    /// Deflate level, trading upload time for a smaller archive
+    compression_level: Option<u32>,
+    idle_expiry: Option<Duration>,
+    /// Used as the record id in place of a random name
+    slug: Option<String>,
+    burn: bool,
+    max_downloads: u8,
+    lifetime: Duration,
+}
+
+impl UploadOptions {
+    // Idle expiry is meant for links that see regular use, a year is plenty
+    const MAX_IDLE_EXPIRY_DAYS: u64 = 365;
+    // Longer lived links can still be extended by an admin
+    const MAX_EXPIRY_DAYS: u64 = 30;
+
+    pub fn new(config: &Config) -> Self {
+        Self {
+            format: ArchiveFormat::default(),
+            compression: config.compression,
+            compression_level: None,
+            idle_expiry: None,
+            slug: None,
+            burn: false,
+            max_downloads: UploadRecord::DEFAULT_MAX_DOWNLOADS,
+            lifetime: UploadRecord::DEFAULT_LIFETIME,
+        }
+    }
+
+    pub fn slug(&self) -> Option<&str> {
+        self.slug.as_deref()
+    }
+
+    pub fn is_option(name: &str) -> bool {
+        matches!(
+            name,
+            "format"
+                | "compression"
+                | "compression_level"
+                | "idle_expiry_days"
+                | "slug"
+                | "burn"
+                | "max_downloads"
+                | "expiry_days"
+        )
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), (StatusCode, String)> {
+        match name {
+            "format" => {
+                self.format = ArchiveFormat::parse(value).ok_or_else(|| {
+                    (
+                        StatusCode::BAD_REQUEST,
+                        format!("Unknown archive format: {value:?}"),
+                    )
+                })?;
+            }
+            "compression" => {
+                self.compression = util::parse_compression(value).ok_or_else(|| {
+                    (
+                        StatusCode::BAD_REQUEST,
+                        format!("Unknown compression method: {value:?}"),
+                    )
+                })?;
+            }
+            "compression_level" => {
+                let range = ArchiveWriter::MIN_LEVEL..=ArchiveWriter::MAX_LEVEL;
+                let level = value
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|level| range.contains(level))
+                    .ok_or_else(|| {
+                        (
+                            StatusCode::BAD_REQUEST,
+                            format!(
+                                "compression_level must be between {} and {}",
+                                range.start(),
+                                range.end()
+                            ),
+                        )
+                    })?;
+                self.compression_level = Some(level);
+            }
+            "idle_expiry_days" => {
+                let days: u64 = value
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|days| *days <= Self::MAX_IDLE_EXPIRY_DAYS)
+                    .ok_or_else(|| {
+                        (
+                            StatusCode::BAD_REQUEST,
+                            format!(
+                                "idle_expiry_days must be between 0 and {}",
+                                Self::MAX_IDLE_EXPIRY_DAYS
+                            ),
+                        )
+                    })?;
+                // 0 keeps the link alive for its full lifetime no matter the activity
+                self.idle_expiry = (days > 0).then(|| Duration::from_secs(days * 24 * 60 * 60));
+            }
+            "slug" => {
+                // An empty slug field means the uploader left it blank
+                self.slug = if value.trim().is_empty() {
+                    None
+                } else {
+                    Some(util::validate_slug(value).map_err(|err| (StatusCode::BAD_REQUEST, err))?)
+                };
+            }
+            "burn" => {
+                // Checkboxes send "on", and only when they're ticked
+                self.burn = match value.trim().to_ascii_lowercase().as_str() {
+                    "on" | "true" | "yes" | "1" => true,
+                    "" | "off" | "false" | "no" | "0" => false,
+                    _ => {
+                        return Err((
+                            StatusCode::BAD_REQUEST,
+                            format!("burn must be true or false, got {value:?}"),
+                        ))
+                    }
+                };
+            }
+            "max_downloads" => {
+                let max: u64 = value
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|max| *max > 0)
+                    .ok_or_else(|| {
+                        (
+                            StatusCode::BAD_REQUEST,
+                            "max_downloads must be a positive number".to_string(),
+                        )
+                    })?;
+                // Anything past what the record can count to is as good as unlimited
+                self.max_downloads = u8::try_from(max).unwrap_or(u8::MAX);
+            }
+            "expiry_days" => {
+                let days: u64 = value
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|days| (1..=Self::MAX_EXPIRY_DAYS).contains(days))
+                    .ok_or_else(|| {
+                        (
+                            StatusCode::BAD_REQUEST,
+                            format!(
+                                "expiry_days must be between 1 and {}",
+                                Self::MAX_EXPIRY_DAYS
+                            ),
+                        )
+                    })?;
+                self.lifetime = Duration::from_secs(days * 24 * 60 * 60);
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+pub fn out_of_storage() -> (StatusCode, String) {
+    (
+        StatusCode::INSUFFICIENT_STORAGE,
+        "The server is out of space for uploads, please try again later".to_string(),
+    )
+}
+
+pub fn slug_taken(slug: &str) -> (StatusCode, String) {
+    (
+        StatusCode::CONFLICT,
+        format!("The link {slug:?} is already taken"),
+    )
+}
+
+// Formats that are already compressed only burn cpu when deflated again
+static INCOMPRESSIBLE_EXTENSIONS: [&str; 24] = [
+    "7z", "aac", "avif", "br", "bz2", "flac", "gif", "gz", "heic", "jpeg", "jpg", "m4a", "mkv",
+    "mov", "mp3", "mp4", "ogg", "opus", "png", "rar", "webm", "webp", "xz", "zip",
+];
+
+fn entry_compression(file_name: &str, compression: Compression) -> Compression {
+    let incompressible = file_name.rsplit_once('.').is_some_and(|(_, extension)| {
+        INCOMPRESSIBLE_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+    });
+
+    if incompressible {
+        Compression::Stored
+    } else {
+        compression
+    }
+}
+
+/// What the JSON upload api hands back to its caller
+#[derive(Debug, Serialize)]
+pub struct UploadResponse {
+    pub id: String,
+    /// Absolute url that downloads the archive directly
+    pub url: String,
+    pub expires_at: DateTime<Utc>,
+    pub max_downloads: u8,
+    /// Send as `X-Delete-Token` with `DELETE /link/:id` to remove the upload
+    pub delete_token: String,
+}
+
+impl UploadResponse {
+    pub fn new(upload: StoredUpload, base_url: &str) -> Self {
+        Self {
+            url: format!("{}/download/{}", base_url, upload.id),
+            id: upload.id,
+            expires_at: upload.record.expires_at(),
+            max_downloads: upload.record.max_downloads,
+            delete_token: upload.delete_token,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{archive, remote, test_util};
+
+    #[tokio::test]
+    async fn repeated_names_get_a_counter() {
+        let state = test_util::state().await;
+
+        let form = test_util::Form::new()
+            .file("report.pdf", b"first")
+            .file("report.pdf", b"second");
+        let (_, record) = test_util::upload(&state, form).await;
+
+        let names: Vec<_> = archive::list_entries(record.format, &record.file)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, ["report.pdf", "report (1).pdf"]);
+    }
+
+    #[tokio::test]
+    async fn too_many_files_are_refused() {
+        let state = test_util::state_with(Config {
+            max_entries: 2,
+            ..test_util::config()
+        })
+        .await;
+
+        let form = test_util::Form::new()
+            .file("one.txt", b"1")
+            .file("two.txt", b"2")
+            .file("three.txt", b"3");
+        let response = test_util::send(&state, form.post("/api/upload")).await;
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(state.records.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn compressed_formats_are_stored() {
+        let state = test_util::state().await;
+
+        let form = test_util::Form::new()
+            .file("cat.png", b"not really a png")
+            .file("cat.txt", b"meow meow meow meow");
+        let (_, record) = test_util::upload(&state, form).await;
+
+        let reader = async_zip::tokio::read::fs::ZipFileReader::new(&record.file)
+            .await
+            .unwrap();
+        let compression: Vec<_> = reader
+            .file()
+            .entries()
+            .iter()
+            .map(|stored| (stored.entry().filename(), stored.entry().compression()))
+            .collect();
+        assert_eq!(
+            compression,
+            [
+                ("cat.png", Compression::Stored),
+                ("cat.txt", Compression::Deflate)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn taken_slugs_conflict() {
+        let state = test_util::state().await;
+
+        let form = test_util::Form::new()
+            .text("slug", "cat-pics")
+            .file("cat.txt", b"meow");
+        let (id, _) = test_util::upload(&state, form).await;
+        assert_eq!(id, "cat-pics");
+
+        let form = test_util::Form::new()
+            .text("slug", "cat-pics")
+            .file("dog.txt", b"woof");
+        let response = test_util::send(&state, form.post("/api/upload")).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(state.records.lock().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn invalid_slugs_are_refused() {
+        let state = test_util::state().await;
+
+        for slug in ["../escape", "records", "no"] {
+            let form = test_util::Form::new()
+                .text("slug", slug)
+                .file("cat.txt", b"meow");
+            let response = test_util::send(&state, form.post("/api/upload")).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{slug:?}");
+        }
+        assert!(state.records.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sizes_cover_every_file() {
+        let state = test_util::state().await;
+
+        let form = test_util::Form::new()
+            .file("hello.txt", b"hello")
+            .file("world.txt", b"world!!");
+        let (_, record) = test_util::upload(&state, form).await;
+
+        assert_eq!(record.uncompressed_size, 12);
+        let on_disk = tokio::fs::metadata(&record.file).await.unwrap().len();
+        assert_eq!(record.size, on_disk);
+    }
+
+    #[tokio::test]
+    async fn truncated_forms_fail_cleanly() {
+        let state = test_util::state().await;
+
+        let form = test_util::Form::new().file("cat.txt", &[b'm'; 4096]);
+        let response = test_util::send(&state, form.post_truncated("/api/upload")).await;
+
+        let status = response.status();
+        assert!(
+            status.is_client_error() || status.is_server_error(),
+            "{status}"
+        );
+        assert!(state.records.lock().await.is_empty());
+
+        // The partial archive doesn't outlive the failed upload
+        let mut leftovers = tokio::fs::read_dir(state.config.serve_dir()).await.unwrap();
+        assert!(leftovers.next_entry().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn finished_archives_lose_the_part_extension() {
+        let state = test_util::state().await;
+
+        let form = test_util::Form::new().file("cat.txt", b"meow");
+        let (_, record) = test_util::upload(&state, form).await;
+
+        assert_eq!(record.file.extension().unwrap(), "zip");
+        assert!(tokio::fs::try_exists(&record.file).await.unwrap());
+
+        let mut files = tokio::fs::read_dir(state.config.serve_dir()).await.unwrap();
+        while let Some(file) = files.next_entry().await.unwrap() {
+            assert_eq!(file.path(), record.file);
+        }
+    }
+
+    #[tokio::test]
+    async fn shared_archives_outlive_one_record() {
+        let state = test_util::state().await;
+
+        let form = || test_util::Form::new().file("cat.txt", b"meow");
+        let (first, first_record) = test_util::upload(&state, form()).await;
+        let (second, second_record) = test_util::upload(&state, form()).await;
+        assert_ne!(first, second);
+        assert_eq!(first_record.file, second_record.file);
+
+        let delete = axum::http::Request::delete(format!("/records/{first}"))
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let response = test_util::send(&state, test_util::as_admin(delete)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        assert!(tokio::fs::try_exists(&second_record.file).await.unwrap());
+        let response =
+            test_util::send(&state, test_util::get(&format!("/download/{second}"))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn pending_tus_uploads_hold_their_room() {
+        let state = test_util::state_with(Config {
+            max_storage: Some(1000),
+            ..test_util::config()
+        })
+        .await;
+
+        // Nothing has arrived yet, but all 1000 bytes are spoken for
+        let response = test_util::send(&state, test_util::tus_create(1000, "big.bin")).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let form = test_util::Form::new().file("cat.txt", b"meow");
+        let response = test_util::send(&state, form.post("/api/upload")).await;
+        assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+
+        let response = test_util::send(&state, test_util::tus_create(1, "cat.txt")).await;
+        assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+        assert!(state.records.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn stored_archives_fill_the_ceiling() {
+        let state = test_util::state_with(Config {
+            max_storage: Some(100),
+            ..test_util::config()
+        })
+        .await;
+        // Zip headers alone take this past 100 bytes
+        test_util::insert_upload(&state, "cat", &[("cat.txt", "meow")], Default::default()).await;
+        assert_eq!(state.storage_room().await, Some(0));
+
+        let form = test_util::Form::new().file("dog.txt", b"woof");
+        let response = test_util::send(&state, form.post("/api/upload")).await;
+        assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(state.records.lock().await.len(), 1);
+    }
+
+    #[test]
+    fn max_downloads_clamp_to_what_records_count() {
+        let config = Config::default();
+        let max_downloads = |value: &str| {
+            let mut options = UploadOptions::new(&config);
+            options
+                .set("max_downloads", value)
+                .map(|_| options.max_downloads)
+        };
+
+        assert_eq!(max_downloads("1").unwrap(), 1);
+        assert_eq!(max_downloads("255").unwrap(), 255);
+        assert_eq!(max_downloads("256").unwrap(), u8::MAX);
+        assert_eq!(max_downloads("99999999999").unwrap(), u8::MAX);
+        assert!(max_downloads("0").is_err());
+        assert!(max_downloads("-1").is_err());
+    }
+
+    #[tokio::test]
+    async fn compression_levels_change_the_size() {
+        let state = test_util::state().await;
+        let contents = "meow meow, purr ".repeat(4096);
+
+        let size_at = |level: &str| {
+            let form = test_util::Form::new()
+                .text("compression_level", level)
+                .file("cat.txt", contents.as_bytes());
+            let state = state.clone();
+            async move { test_util::upload(&state, form).await.1.size }
+        };
+
+        let stored = size_at("0").await;
+        let squeezed = size_at("9").await;
+        assert!(stored > contents.len() as u64, "{stored}");
+        assert!(squeezed < stored / 10, "{squeezed} vs {stored}");
+
+        let form = test_util::Form::new()
+            .text("compression_level", "10")
+            .file("cat.txt", b"meow");
+        let response = test_util::send(&state, form.post("/api/upload")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn uploads_land_in_the_data_dir() {
+        let data_dir = test_util::temp_dir().join("elsewhere");
+        let state = test_util::state_with(Config {
+            data_dir: data_dir.clone(),
+            ..test_util::config()
+        })
+        .await;
+
+        let form = test_util::Form::new().file("cat.txt", b"meow");
+        let (_, record) = test_util::upload(&state, form).await;
+
+        assert_eq!(record.file.parent().unwrap(), data_dir.join("serve"));
+        assert!(tokio::fs::try_exists(&record.file).await.unwrap());
+        assert!(tokio::fs::try_exists(data_dir.join("data")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn hostile_names_stay_inside_the_archive() {
+        let state = test_util::state().await;
+
+        let form = test_util::Form::new()
+            .file("../../escape.txt", b"meow")
+            .file("/etc/passwd", b"root")
+            .file("..\\..\\evil.bat", b"del");
+        let (_, record) = test_util::upload(&state, form).await;
+
+        let entries = archive::list_entries(record.format, &record.file)
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 3);
+        for entry in entries {
+            assert!(
+                !entry.name.contains(['/', '\\']) && entry.name != "..",
+                "{:?}",
+                entry.name
+            );
+        }
+    }
+
+    // Hands back the same bytes for any url
+    struct StubSource(&'static [u8]);
+
+    #[async_trait::async_trait]
+    impl remote::Source for StubSource {
+        async fn fetch(
+            &self,
+            _url: &str,
+        ) -> Result<(String, archive::EntryReader), (StatusCode, String)> {
+            Ok((
+                "fetched.txt".to_owned(),
+                Box::pin(std::io::Cursor::new(self.0)),
+            ))
+        }
+    }
+
+    async fn stubbed_state(config: Config, body: &'static [u8]) -> AppState {
+        let mut state = test_util::state_with(config).await;
+        state.remote = std::sync::Arc::new(StubSource(body));
+        state
+    }
+
+    #[tokio::test]
+    async fn fetched_urls_are_archived() {
+        let state = stubbed_state(test_util::config(), b"fetched meow").await;
+
+        let form = test_util::Form::new()
+            .text("url", "http://example.test/cat")
+            .file("cat.txt", b"meow");
+        let (_, record) = test_util::upload(&state, form).await;
+
+        let entries = archive::list_entries(record.format, &record.file)
+            .await
+            .unwrap();
+        let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["fetched.txt", "cat.txt"]);
+        assert_eq!(record.uncompressed_size, 16);
+    }
+
+    #[tokio::test]
+    async fn fetched_urls_count_toward_the_size_cap() {
+        let config = Config {
+            max_upload_size: Some(8),
+            ..test_util::config()
+        };
+        let state = stubbed_state(config, b"far too much meowing").await;
+
+        let form = test_util::Form::new().text("url", "http://example.test/cat");
+        let response = test_util::send(&state, form.post("/api/upload")).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(state.records.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn oversized_uploads_leave_nothing_behind() {
+        let state = test_util::state_with(Config {
+            max_upload_size: Some(8),
+            ..test_util::config()
+        })
+        .await;
+
+        let form = test_util::Form::new()
+            .file("cat.txt", b"meow")
+            .file("dog.txt", b"woof woof");
+        let response = test_util::send(&state, form.post("/api/upload")).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(state.records.lock().await.is_empty());
+
+        let mut leftovers = tokio::fs::read_dir(state.config.serve_dir()).await.unwrap();
+        assert!(leftovers.next_entry().await.unwrap().is_none());
+
+        // Right at the cap is fine
+        let form = test_util::Form::new().file("cat.txt", b"meowmeow");
+        test_util::upload(&state, form).await;
+    }
+}
@@ -4,7 +4,17 @@ use rand::{
     SeedableRng,
 };
 
-use std::{io, path::Path};
+use async_zip::Compression;
+
+use chrono::{DateTime, Utc};
+
+use headers::HeaderMap;
+
+use sanitize_filename_reader_friendly::sanitize;
+
+use std::{collections::HashSet, io, path::Path};
+
+use unicode_normalization::UnicodeNormalization;
 
 #[inline]
 pub async fn make_dir<T>(name: T) -> io::Result<()>
@@ -26,7 +36,182 @@ pub fn get_random_name(len: usize) -> String {
     Alphanumeric.sample_string(&mut rng, len)
 }
 
-#[allow(dead_code)]
+// Zip tools tend to only extract one of several entries sharing a name, so
+// repeats get a counter before the extension: `report.pdf`, `report (1).pdf`.
+// The stem is cut short to fit the counter in `max_len` bytes, cutting the
+// counter itself would leave the name taken.
+pub fn unique_name(name: String, used: &mut HashSet<String>, max_len: usize) -> String {
+    if used.insert(name.clone()) {
+        return name;
+    }
+
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{extension}")),
+        _ => (name.as_str(), String::new()),
+    };
+
+    let mut count = 1;
+    loop {
+        let counter = format!(" ({count})");
+        let extension = if counter.len() + extension.len() < max_len {
+            extension.as_str()
+        } else {
+            ""
+        };
+
+        let mut end = max_len
+            .saturating_sub(counter.len() + extension.len())
+            .min(stem.len());
+        while !stem.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        let candidate = format!("{}{counter}{extension}", &stem[..end]);
+        if used.insert(candidate.clone()) {
+            return candidate;
+        }
+        count += 1;
+    }
+}
+
+/// Sanitizes an uploaded file name down to a single path segment, so nothing
+/// that ever extracts an entry by name can be walked out of its directory, and
+/// to at most `max_len` bytes
+pub fn safe_entry_name(name: &str, max_len: usize) -> String {
+    // Normalized so the composed and decomposed spellings of a name, which look
+    // the same, end up as the same entry
+    let name: String = sanitize(name)
+        .nfc()
+        .filter(|c| !c.is_control())
+        .map(|c| if matches!(c, '/' | '\\') { '_' } else { c })
+        .collect();
+
+    // Empty names and ones like `..` would still mean something to a filesystem
+    if name.chars().all(|c| c == '.') {
+        "unnamed".to_string()
+    } else {
+        truncate_name(name, max_len)
+    }
+}
+
+// Keeps the extension when there's room left for some of the stem, so the file
+// still opens with the right program
+fn truncate_name(name: String, max_len: usize) -> String {
+    if name.len() <= max_len {
+        return name;
+    }
+
+    let extension = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() && extension.len() + 1 < max_len => {
+            &name[name.len() - extension.len() - 1..]
+        }
+        _ => "",
+    };
+
+    let mut end = max_len - extension.len();
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    format!("{}{}", &name[..end], extension)
+}
+
+pub fn parse_compression(method: &str) -> Option<Compression> {
+    match method.trim().to_ascii_lowercase().as_str() {
+        "stored" | "store" | "none" => Some(Compression::Stored),
+        "deflate" => Some(Compression::Deflate),
+        _ => None,
+    }
+}
+
+/// Describes `then` relative to `now`, e.g. "3 hours ago" or "in 2 days"
+pub fn relative_time(then: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let delta = then.signed_duration_since(now);
+    let past = delta < chrono::Duration::zero();
+    let delta = if past { -delta } else { delta };
+
+    let (count, unit) = if delta.num_days() > 0 {
+        (delta.num_days(), "day")
+    } else if delta.num_hours() > 0 {
+        (delta.num_hours(), "hour")
+    } else if delta.num_minutes() > 0 {
+        (delta.num_minutes(), "minute")
+    } else if past {
+        return "just now".to_string();
+    } else {
+        return "any moment now".to_string();
+    };
+
+    let plural = if count > 1 { "s" } else { "" };
+    if past {
+        format!("{count} {unit}{plural} ago")
+    } else {
+        format!("in {count} {unit}{plural}")
+    }
+}
+
+// Anything the router or static files already answer to can't be a slug
+static RESERVED_SLUGS: [&str; 13] = [
+    "404", "api", "css", "download", "favicon", "healthz", "link", "metrics", "readyz", "records",
+    "scripts", "tus", "upload",
+];
+
+/// Normalizes a user picked slug to lowercase with dashes for spaces, only
+/// accepting ascii letters, digits, `-` and `_` so it stays a single url segment
+pub fn validate_slug(slug: &str) -> Result<String, String> {
+    let slug = slug
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join("-")
+        .to_ascii_lowercase();
+
+    if !(3..=64).contains(&slug.len()) {
+        return Err("Links must be between 3 and 64 characters long".to_string());
+    }
+
+    if !slug
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err("Links may only contain letters, numbers, '-' and '_'".to_string());
+    }
+
+    if RESERVED_SLUGS.contains(&slug.as_str()) {
+        return Err(format!("{slug:?} is reserved"));
+    }
+
+    Ok(slug)
+}
+
+// Browsers and htmx get pages, anything else (curl, scripts) gets data
+pub fn wants_html(headers: &HeaderMap) -> bool {
+    headers.contains_key("hx-request")
+        || headers
+            .get("accept")
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains("text/html"))
+}
+
+/// Whether the client's `If-None-Match` already covers `etag`, weak tags included
+pub fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get("if-none-match")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+        })
+}
+
+pub fn wants_json(headers: &HeaderMap) -> bool {
+    headers
+        .get("accept")
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"))
+}
+
 pub static UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
 
 // This function is actually rather interesting to me, I understand that rust is
@@ -37,13 +222,107 @@ pub static UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
 // although this function shouldn't be able to panic at runtime due to known bounds
 // being listened to
 #[inline]
-pub fn _bytes_to_human_readable(bytes: u64) -> String {
+pub fn bytes_to_human_readable(bytes: u64) -> String {
     let mut running = bytes as f64;
     let mut count = 0;
-    while running > 1024.0 && count <= 6 {
+    while running >= 1024.0 && count < UNITS.len() {
         running /= 1024.0;
         count += 1;
     }
 
+    if count == 0 {
+        return format!("{} B", bytes);
+    }
+
     format!("{:.2} {}", running, UNITS[count - 1])
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hostile_names_stay_one_segment() {
+        let names = [
+            "../../etc/passwd",
+            "/etc/passwd",
+            "..\\..\\windows\\system32\\config",
+            "C:\\evil.exe",
+            "cat\0.txt",
+            "..",
+            ".",
+            "",
+        ];
+
+        for name in names {
+            let safe = safe_entry_name(name, 255);
+            assert!(!safe.is_empty(), "{name:?}");
+            assert!(!safe.chars().all(|c| c == '.'), "{name:?} -> {safe:?}");
+            assert!(!safe.contains(['/', '\\', '\0']), "{name:?} -> {safe:?}");
+        }
+    }
+
+    #[test]
+    fn long_names_keep_their_extension() {
+        let name = format!("{}.pdf", "a".repeat(500));
+
+        let safe = safe_entry_name(&name, 255);
+        assert_eq!(safe.len(), 255);
+        assert!(safe.ends_with("a.pdf"));
+    }
+
+    #[test]
+    fn composed_and_decomposed_names_match() {
+        let composed = "caf\u{e9}.txt";
+        let decomposed = "cafe\u{301}.txt";
+
+        assert_eq!(
+            safe_entry_name(composed, 255),
+            safe_entry_name(decomposed, 255)
+        );
+    }
+
+    #[test]
+    fn counters_fit_within_the_limit() {
+        let name = safe_entry_name(&format!("{}.pdf", "a".repeat(500)), 255);
+        let mut used = HashSet::new();
+
+        let first = unique_name(name.clone(), &mut used, 255);
+        let second = unique_name(name.clone(), &mut used, 255);
+        let third = unique_name(name, &mut used, 255);
+
+        assert_eq!(first.len(), 255);
+        assert!(second.len() <= 255 && second.ends_with(" (1).pdf"));
+        assert!(third.len() <= 255 && third.ends_with(" (2).pdf"));
+    }
+
+    #[test]
+    fn counters_respect_char_boundaries() {
+        let name = "é".repeat(10);
+        let mut used = HashSet::from([name.clone()]);
+
+        let renamed = unique_name(name, &mut used, 11);
+        assert_eq!(renamed, "ééé (1)");
+    }
+
+    #[test]
+    fn relative_times_pick_the_largest_unit() {
+        let now: DateTime<Utc> = "2023-07-10T12:00:00Z".parse().unwrap();
+        let ago = |secs| relative_time(now - chrono::Duration::seconds(secs), now);
+        let ahead = |secs| relative_time(now + chrono::Duration::seconds(secs), now);
+
+        assert_eq!(ago(0), "just now");
+        assert_eq!(ago(59), "just now");
+        assert_eq!(ago(60), "1 minute ago");
+        assert_eq!(ago(150), "2 minutes ago");
+        assert_eq!(ago(60 * 60), "1 hour ago");
+        assert_eq!(ago(5 * 60 * 60 + 59), "5 hours ago");
+        assert_eq!(ago(24 * 60 * 60), "1 day ago");
+        assert_eq!(ago(3 * 24 * 60 * 60), "3 days ago");
+
+        assert_eq!(ahead(30), "any moment now");
+        assert_eq!(ahead(2 * 60), "in 2 minutes");
+        assert_eq!(ahead(60 * 60), "in 1 hour");
+        assert_eq!(ahead(2 * 24 * 60 * 60 + 1), "in 2 days");
+    }
+}
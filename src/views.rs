@@ -1,20 +1,33 @@
+use chrono::Utc;
 use futures::TryFutureExt;
 use leptos::{component, view, Children, IntoView, Scope};
 use serde::Deserialize;
 
-use crate::state::UploadRecord;
+use std::time::Duration;
+
+use crate::{archive::ArchiveEntry, state::UploadRecord, util};
 
 #[derive(Debug, Deserialize)]
 pub struct CatFact {
     pub fact: String,
 }
 
-pub async fn get_cat_fact() -> String {
-    reqwest::get("https://catfact.ninja/fact")
+pub static CAT_FACT_FALLBACK: &str = "The cat fact goddess has failed me :<";
+
+pub async fn get_cat_fact(client: &reqwest::Client) -> Option<String> {
+    client
+        .get("https://catfact.ninja/fact")
+        .timeout(Duration::from_secs(3))
+        .send()
         .and_then(|res| res.json())
         .map_ok(|cf: CatFact| cf.fact)
         .await
-        .unwrap_or_else(|_| String::from("The cat fact goddess has failed me :<"))
+        .ok()
+}
+
+pub fn remaining_message(downloads_remaining: u8) -> String {
+    let plural = if downloads_remaining > 1 { "s" } else { "" };
+    format!("You have {downloads_remaining} download{plural} remaining!")
 }
 
 // {https://api.thecatapi.com/v1/images/search?size=small&format=src}
@@ -38,6 +51,8 @@ pub fn WelcomeView(cx: Scope, fact: String) -> impl IntoView {
             <div class="cat-img-wrapper">
                 <img class="cat-img" src="https://api.thecatapi.com/v1/images/search?size=small&format=src" />
             </div>
+            <input type="text" id="slug" name="slug" placeholder="Custom link (optional)" />
+            <label><input type="checkbox" name="burn" />" Delete after the first download"</label>
             <input type="file" id="file" name="file" data-multiple-caption="{{count}} files selected" multiple />
             <label for="file">Select Files</label>
 
@@ -53,11 +68,40 @@ pub fn WelcomeView(cx: Scope, fact: String) -> impl IntoView {
 // #TODO: Handle pushing cleaner
 #[component]
 pub fn DownloadLinkPage(cx: Scope, id: String, record: UploadRecord) -> impl IntoView {
+    let contents_url = format!("/link/{id}/contents");
     view! { cx,
         <HtmxPage>
             <div class="form-wrapper">
                 <LinkView id record />
             </div>
+            <div class="link-wrapper" hx-get=contents_url hx-trigger="load">
+                Peeking inside...
+            </div>
+        </HtmxPage>
+    }
+}
+
+#[component]
+pub fn ContentsView(cx: Scope, entries: Vec<ArchiveEntry>) -> impl IntoView {
+    view! { cx,
+        <ul>
+            {entries
+                .into_iter()
+                .map(|entry| {
+                    let size = util::bytes_to_human_readable(entry.size);
+                    view! { cx, <li>{entry.name} " (" {size} ")"</li> }
+                })
+                .collect::<Vec<_>>()}
+        </ul>
+    }
+}
+
+#[component]
+pub fn NotFoundPage(cx: Scope) -> impl IntoView {
+    view! { cx,
+        <HtmxPage>
+            <h2>"Link not found 3:"</h2>
+            <a class="return-button" href="/">"Return to home"</a>
         </HtmxPage>
     }
 }
@@ -84,20 +128,43 @@ pub fn HtmxPage(cx: Scope, children: Children) -> impl IntoView {
 }
 
 #[component]
-pub fn LinkView(cx: Scope, id: String, record: UploadRecord) -> impl IntoView {
-    let downloads_remaining = record.max_downloads - record.downloads;
-    let plural = if downloads_remaining > 1 { "s" } else { "" };
+pub fn LinkView(
+    cx: Scope,
+    id: String,
+    record: UploadRecord,
+    /// Only set for the uploader, lets them take the upload down again
+    #[prop(optional_no_strip)]
+    delete_token: Option<String>,
+) -> impl IntoView {
+    let delete_button = delete_token.map(|token| {
+        view! { cx,
+            <button class="return-button"
+                hx-delete=format!("/link/{id}")
+                hx-headers=format!(r#"{{"X-Delete-Token": "{token}"}}"#)
+                hx-confirm="Delete this upload for everyone?"
+                hx-target="closest .column-container"
+                hx-swap="outerHTML">Delete Upload</button>
+        }
+    });
+
+    let now = Utc::now();
+    let uploaded = util::relative_time(record.uploaded, now);
+    let expires = util::relative_time(record.expires_at(), now);
+
     view! {
         cx,
         <div class="column-container">
             <div class="link-wrapper">
                 <a id="link" href="/download/{id}">Download Now!</a>
             </div>
+            <p>"Uploaded " {uploaded} ", expires " {expires}</p>
 
             <div class="link-wrapper" hx-get="/link/{id}/remaining" hx-trigger="click from:#link delay:0.2s, every 10s" >
-                You have {record.downloads_remaining()} download{plural} remaining!
+                {remaining_message(record.downloads_remaining())}
             </div>
+            <img class="qr-code" src=format!("/link/{id}/qr") alt="QR code for this link" />
             <button class="return-button" onclick="clipboard()">Copy to Clipboard</button>
+            {delete_button}
 
 
             <a href="/" class="return-button">Return to home</a>